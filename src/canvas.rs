@@ -0,0 +1,388 @@
+use glam::Vec2;
+
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::raster::{self, Band, Command, Pen, Shape};
+
+/// Rows rasterized together, small enough for the work to spread evenly over the threads.
+const BAND_ROWS: usize = 16;
+
+/// How the pen color combines with what is already drawn. Colors in the buffer count as
+/// premultiplied by their alpha, which they effectively are for anything that shows them
+/// over black, for every mode but `Blend`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    /// Overwrites the pixel, partly covered edges are blended like `Over`.
+    Replace,
+    /// Straight alpha over.
+    Blend,
+    /// Premultiplied alpha over.
+    Over,
+    /// Adds the colors up, overlapping light gets brighter.
+    Add,
+    /// Brightens like light projected on top, without adding up to harsh white.
+    Screen,
+    /// Darkens by the pen color.
+    Multiply,
+    /// The brighter of both per channel.
+    Lighten,
+}
+
+impl FromStr for BlendMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "replace" => Ok(BlendMode::Replace),
+            "blend" => Ok(BlendMode::Blend),
+            "over" => Ok(BlendMode::Over),
+            "add" => Ok(BlendMode::Add),
+            "screen" => Ok(BlendMode::Screen),
+            "multiply" => Ok(BlendMode::Multiply),
+            "lighten" | "max" => Ok(BlendMode::Lighten),
+            _ => Err(format!("unknown blend mode `{name}`")),
+        }
+    }
+}
+
+/// How open strokes end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineCap {
+    /// Flat at the end point.
+    Butt,
+    Round,
+    /// Flat, half the stroke width past the end point.
+    Square,
+}
+
+/// How strokes go around corners.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineJoin {
+    /// Sharp, cut off like `Bevel` where it would reach further than 4 stroke widths.
+    Miter,
+    Round,
+    Bevel,
+}
+
+/// An RGBA8 image of any size, rows top to bottom. Draw calls are recorded with the pen
+/// as it is at the time and drawn all at once, bands of rows in parallel, when the pixels
+/// are needed. The recorded calls are kept as a display list of the image until it is
+/// cleared, e.g. to write it as SVG.
+pub struct Canvas {
+    buffer: Vec<u8>,
+    width: usize,
+    height: usize,
+    palette: Vec<[u8; 4]>,
+    pub pen_color: [u8; 4],
+    pub blend_mode: BlendMode,
+    /// Draw lines, curves and circles with smooth edges.
+    pub antialias: bool,
+    /// Width of lines, curves and outlines in pixels.
+    pub stroke_width: f32,
+    pub line_cap: LineCap,
+    pub line_join: LineJoin,
+    /// Draw calls since the canvas was last cleared.
+    commands: Vec<Command>,
+    /// How many of `commands` are in the pixels already.
+    drawn: usize,
+    /// What the canvas was last cleared to.
+    background: [u8; 4],
+}
+
+impl Canvas {
+    pub fn new(palette: Vec<[u8; 4]>, width: usize, height: usize) -> Self {
+        let buffer = vec![255; width * height * 4];
+        let pen_color = [255, 255, 255, 255];
+        Self {
+            buffer,
+            width,
+            height,
+            palette,
+            pen_color,
+            blend_mode: BlendMode::Replace,
+            antialias: false,
+            stroke_width: 1.0,
+            line_cap: LineCap::Butt,
+            line_join: LineJoin::Round,
+            commands: Vec::new(),
+            drawn: 0,
+            background: [255; 4],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The image with everything drawn so far.
+    pub fn pixels(&mut self) -> &mut [u8] {
+        self.flush();
+        &mut self.buffer
+    }
+
+    /// The image with everything drawn so far, and the display list of what was drawn
+    /// since the canvas was last cleared, faded or set, in order.
+    pub fn contents(&mut self) -> (&[u8], &[Command]) {
+        self.flush();
+        (&self.buffer, &self.commands)
+    }
+
+    /// The color the display list is drawn on, premultiplied.
+    pub fn background(&self) -> [u8; 4] {
+        self.background
+    }
+
+    /// Replaces the image with `pixels`, which have nothing recorded on them.
+    pub fn set_pixels(&mut self, pixels: &[u8]) {
+        self.forget();
+        self.buffer.copy_from_slice(pixels);
+    }
+
+    pub fn select_color(&mut self, color: u8) {
+        self.pen_color = self.palette[color as usize % self.palette.len()]
+    }
+
+    /// Fills the whole canvas with `color`.
+    pub fn clear(&mut self, color: [u8; 4]) {
+        // Whatever they would have drawn gets covered anyway.
+        self.forget();
+        self.background = color;
+        for pixel in self.buffer.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+
+    /// Moves every channel of every pixel towards `color`, keeping `keep` in 0..=1 of the
+    /// difference. Pixels reach `color` exactly once it is small enough, instead of
+    /// getting stuck a step away.
+    pub fn fade(&mut self, color: [u8; 4], keep: f32) {
+        self.flush();
+        // What's left of them is no longer what they drew, the list starts over.
+        self.forget();
+        self.background = color;
+        let keep = keep.clamp(0.0, 1.0);
+        // A table per channel is quicker than doing the math on every byte.
+        let mut tables = [[0u8; 256]; 4];
+        for (table, target) in tables.iter_mut().zip(color) {
+            for (value, faded) in table.iter_mut().enumerate() {
+                let difference = (value as f32 - target as f32) * keep;
+                *faded = (target as f32 + difference.trunc()) as u8;
+            }
+        }
+        for pixel in self.buffer.chunks_exact_mut(4) {
+            for (value, table) in pixel.iter_mut().zip(&tables) {
+                *value = table[*value as usize];
+            }
+        }
+    }
+
+    pub fn random(&mut self) {
+        self.flush();
+        self.forget();
+        for pixel in self.buffer.chunks_exact_mut(4) {
+            let mut change = self.palette[fastrand::usize(0..self.palette.len())];
+            change[3] = (change[3] as f32 * 0.05) as u8;
+            raster::blend(pixel, change, BlendMode::Over, 1.0);
+        }
+    }
+
+    pub fn draw_curve(&mut self, start: Vec2, control: Vec2, end: Vec2) {
+        self.record(Shape::Curve(start, control, end));
+    }
+
+    /// Lines `stroke_width` wide, antialiased if they are thin. Coordinates name the same
+    /// pixels as `draw_point`, `(10, 10)` is the middle of pixel 10, 10.
+    pub fn draw_line(&mut self, from: Vec2, to: Vec2) {
+        self.record(Shape::Line(from, to));
+    }
+
+    /// A line through `points`, `stroke_width` wide with `line_cap` at the ends and
+    /// `line_join` at the corners. A `closed` one goes back to the first point.
+    pub fn stroke(&mut self, points: &[Vec2], closed: bool) {
+        self.record(Shape::Stroke {
+            points: points.to_vec(),
+            closed,
+        });
+    }
+
+    pub fn fill_circle(&mut self, center: Vec2, radius: f32) {
+        self.record(Shape::Circle {
+            center,
+            radius,
+            filled: true,
+        });
+    }
+
+    pub fn stroke_circle(&mut self, center: Vec2, radius: f32) {
+        self.record(Shape::Circle {
+            center,
+            radius,
+            filled: false,
+        });
+    }
+
+    /// A round dot fading out from the middle to `radius`.
+    pub fn draw_dot(&mut self, center: Vec2, radius: f32) {
+        self.record(Shape::Dot { center, radius });
+    }
+
+    pub fn fill_rect(&mut self, top_left: Vec2, bottom_right: Vec2) {
+        self.fill_rounded_rect(top_left, bottom_right, 0.0);
+    }
+
+    pub fn stroke_rect(&mut self, top_left: Vec2, bottom_right: Vec2) {
+        let top_right = Vec2::new(bottom_right.x, top_left.y);
+        let bottom_left = Vec2::new(top_left.x, bottom_right.y);
+        self.stroke(&[top_left, top_right, bottom_right, bottom_left], true);
+    }
+
+    /// A rectangle with its corners rounded off by `radius`.
+    pub fn fill_rounded_rect(&mut self, top_left: Vec2, bottom_right: Vec2, radius: f32) {
+        self.record(Shape::RoundedRect {
+            top_left,
+            bottom_right,
+            radius,
+            filled: true,
+        });
+    }
+
+    pub fn stroke_rounded_rect(&mut self, top_left: Vec2, bottom_right: Vec2, radius: f32) {
+        self.record(Shape::RoundedRect {
+            top_left,
+            bottom_right,
+            radius,
+            filled: false,
+        });
+    }
+
+    /// Fills the inside of `points`, where a ray from it crosses the outline an odd
+    /// number of times.
+    pub fn fill_polygon(&mut self, points: &[Vec2]) {
+        self.record(Shape::Polygon(points.to_vec()));
+    }
+
+    pub fn stroke_polygon(&mut self, points: &[Vec2]) {
+        self.stroke(points, true);
+    }
+
+    pub fn draw_point(&mut self, pos: Vec2) {
+        self.record(Shape::Point(pos));
+    }
+
+    fn record(&mut self, shape: Shape) {
+        let pen = Pen {
+            color: self.pen_color,
+            blend_mode: self.blend_mode,
+            antialias: self.antialias,
+            stroke_width: self.stroke_width,
+            line_cap: self.line_cap,
+            line_join: self.line_join,
+        };
+        self.commands.push(Command { shape, pen });
+    }
+
+    fn forget(&mut self) {
+        self.commands.clear();
+        self.drawn = 0;
+    }
+
+    /// Draws the commands not drawn yet. Every band of rows goes through all of them in
+    /// order, so each pixel is blended the same as if they were drawn one after another.
+    fn flush(&mut self) {
+        if self.drawn == self.commands.len() {
+            return;
+        }
+        let (width, height) = (self.width, self.height);
+        let commands = &self.commands[self.drawn..];
+        let bands = Mutex::new(self.buffer.chunks_mut(BAND_ROWS * width * 4).enumerate());
+        let work = || loop {
+            let Some((idx, pixels)) = bands.lock().unwrap().next() else {
+                break;
+            };
+            let mut band = Band::new(pixels, width, height, idx * BAND_ROWS);
+            for command in commands {
+                band.draw(command);
+            }
+        };
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        if threads == 1 {
+            work();
+        } else {
+            thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(work);
+                }
+            });
+        }
+        self.drawn = self.commands.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Draws shapes that cross band boundaries and the canvas edges, every kind of them.
+    fn draw_scene(canvas: &mut Canvas) {
+        let v = Vec2::new;
+        canvas.clear([20, 30, 40, 255]);
+        canvas.pen_color = [200, 100, 50, 255];
+        canvas.draw_line(v(-5.0, 3.0), v(90.0, 60.0));
+        // Steep, mostly crossing rows.
+        canvas.antialias = true;
+        canvas.draw_line(v(10.0, -4.0), v(14.5, 75.0));
+        canvas.draw_line(v(60.5, 70.0), v(58.0, 2.0));
+        canvas.blend_mode = BlendMode::Over;
+        canvas.pen_color = [90, 120, 30, 160];
+        canvas.stroke_width = 6.5;
+        canvas.line_cap = LineCap::Round;
+        canvas.draw_line(v(5.0, 40.0), v(95.0, 15.0));
+        canvas.line_cap = LineCap::Square;
+        canvas.draw_line(v(30.0, 5.0), v(34.0, 66.0));
+        canvas.line_join = LineJoin::Miter;
+        canvas.stroke(&[v(20.0, 20.0), v(50.0, 47.0), v(80.0, 18.0)], false);
+        canvas.line_join = LineJoin::Bevel;
+        canvas.stroke_polygon(&[v(40.0, 10.0), v(70.0, 30.0), v(45.0, 50.0)]);
+        canvas.stroke_width = 2.5;
+        canvas.draw_curve(v(0.0, 70.0), v(50.0, -30.0), v(100.0, 68.0));
+        canvas.blend_mode = BlendMode::Add;
+        canvas.draw_dot(v(48.0, 31.5), 9.0);
+        canvas.draw_dot(v(97.0, 66.0), 6.0);
+        canvas.blend_mode = BlendMode::Screen;
+        canvas.pen_color = [40, 40, 200, 255];
+        canvas.fill_polygon(&[v(15.0, 12.0), v(85.0, 50.0), v(15.0, 60.0), v(70.0, 8.0)]);
+        canvas.blend_mode = BlendMode::Blend;
+        canvas.fill_circle(v(75.0, 35.0), 12.5);
+        canvas.stroke_circle(v(25.0, 33.0), 17.0);
+        canvas.fill_rounded_rect(v(55.0, 45.0), v(90.0, 69.0), 6.0);
+        canvas.antialias = false;
+        canvas.blend_mode = BlendMode::Lighten;
+        canvas.stroke_rounded_rect(v(3.0, 14.0), v(40.0, 34.0), 4.0);
+        canvas.fill_rect(v(62.0, 12.0), v(71.0, 20.0));
+        canvas.draw_point(v(50.0, 16.0));
+    }
+
+    #[test]
+    fn bands_draw_like_one_pass() {
+        // Several bands, the last one not full.
+        let (width, height) = (100, 70);
+        assert!(height > BAND_ROWS && height % BAND_ROWS != 0);
+        let mut canvas = Canvas::new(vec![[255; 4]], width, height);
+        draw_scene(&mut canvas);
+        let background = canvas.background();
+        let (pixels, commands) = canvas.contents();
+
+        let mut whole: Vec<u8> = background.repeat(width * height);
+        let mut band = Band::new(&mut whole, width, height, 0);
+        for command in commands {
+            band.draw(command);
+        }
+        assert!(pixels == whole.as_slice(), "bands differ from one pass");
+    }
+}
@@ -0,0 +1,311 @@
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+/// Song time of every frame. In real time frames are due on a monotonic clock and the
+/// ones that are already late are dropped; offline every frame advances by exactly one
+/// frame time, so renders don't depend on how fast they run.
+pub struct Clock {
+    frame_time: f64,
+    real_time: bool,
+    /// Show late frames late instead of dropping them, every frame still advances by
+    /// exactly one frame time.
+    keep_late: bool,
+    rate: f64,
+    paused: bool,
+    /// Song time of the current frame.
+    time: f64,
+    /// When the current frame was due, `None` before the first one.
+    due: Option<Instant>,
+    seeked: bool,
+    dropped: u64,
+}
+
+/// What changed since the previous frame.
+pub struct Tick {
+    pub time: f64,
+    /// Song time until the next frame.
+    pub step: f64,
+    /// Whole frame times of simulation the song advanced by.
+    pub steps: usize,
+    /// The time jumped, anything simulated so far is stale.
+    pub seeked: bool,
+}
+
+impl Clock {
+    pub fn new(fps: f64, real_time: bool) -> Self {
+        Self {
+            frame_time: 1.0 / fps,
+            real_time,
+            keep_late: false,
+            rate: 1.0,
+            paused: false,
+            time: 0.0,
+            due: None,
+            seeked: false,
+            dropped: 0,
+        }
+    }
+
+    /// Waits until the next frame is due in real time, and advances the song time.
+    pub fn tick(&mut self) -> Tick {
+        let frames = self.wait();
+        let previous = self.time;
+        let seeked = std::mem::take(&mut self.seeked);
+        // Right after a seek the frame shows the time that was seeked to.
+        if !self.paused && !seeked {
+            self.time += frames as f64 * self.frame_time * self.rate;
+        }
+        let steps = (self.time / self.frame_time).floor() - (previous / self.frame_time).floor();
+        Tick {
+            time: self.time,
+            step: self.step(),
+            steps: if seeked { 0 } else { steps.max(0.0) as usize },
+            seeked,
+        }
+    }
+
+    /// How many frame times passed since the previous frame, 0 for the first one.
+    fn wait(&mut self) -> u32 {
+        let frame = Duration::from_secs_f64(self.frame_time);
+        let Some(due) = self.due else {
+            self.due = Some(Instant::now());
+            return 0;
+        };
+        if !self.real_time {
+            return 1;
+        }
+        let next = due + frame;
+        let now = Instant::now();
+        if now < next {
+            std::thread::sleep(next - now);
+            self.due = Some(next);
+            return 1;
+        }
+        if self.keep_late {
+            self.due = Some(now);
+            return 1;
+        }
+        // Too late for the next frame, catch up to the latest one that is due instead.
+        let frames = ((now - due).as_secs_f64() / self.frame_time).floor() as u32;
+        self.dropped += frames as u64 - 1;
+        self.due = Some(due + frame * frames);
+        frames
+    }
+
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Song time one frame advances by right now.
+    pub fn step(&self) -> f64 {
+        if self.paused {
+            0.0
+        } else {
+            self.frame_time * self.rate
+        }
+    }
+
+    pub fn frame_time(&self) -> f64 {
+        self.frame_time
+    }
+
+    /// Frames are due in real time from now on, e.g. once nothing else paces them.
+    pub fn set_real_time(&mut self, real_time: bool) {
+        self.real_time = real_time;
+        if self.due.is_some() {
+            self.due = Some(Instant::now());
+        }
+    }
+
+    pub fn set_keep_late(&mut self, keep_late: bool) {
+        self.keep_late = keep_late;
+    }
+
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn seek(&mut self, time: f64) {
+        self.time = time.max(0.0);
+        self.seeked = true;
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate.max(0.0);
+    }
+
+    pub fn apply(&mut self, control: Control) {
+        match control {
+            Control::TogglePause => self.set_paused(!self.paused),
+            Control::Seek(time) => self.seek(time),
+            Control::SeekBy(offset) => self.seek(self.time + offset),
+            Control::Rate(rate) => self.set_rate(rate),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    TogglePause,
+    Seek(f64),
+    SeekBy(f64),
+    Rate(f64),
+}
+
+pub const CONTROLS: &str = "\
+controls, typed as a line into the terminal while previewing:
+  p              pause or resume
+  s <seconds>    seek to a time in the song
+  f, b           seek 5 seconds forward or back
+  r <rate>       playback rate, 1 is normal speed";
+
+impl Control {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let command = words.next()?;
+        let mut value = || words.next().and_then(|value| value.parse::<f64>().ok());
+        match command {
+            "p" => Some(Control::TogglePause),
+            "s" => value().map(Control::Seek),
+            "f" => Some(Control::SeekBy(5.0)),
+            "b" => Some(Control::SeekBy(-5.0)),
+            "r" => value().map(Control::Rate),
+            _ => None,
+        }
+    }
+}
+
+/// Reads controls from stdin on a background thread.
+pub fn read_controls() -> Receiver<Control> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            match Control::parse(&line) {
+                Some(control) => {
+                    if sender.send(control).is_err() {
+                        break;
+                    }
+                }
+                None => eprintln!("{CONTROLS}"),
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame times that add up exactly.
+    const FPS: f64 = 4.0;
+
+    #[test]
+    fn offline_frames_advance_one_frame_time() {
+        let mut clock = Clock::new(FPS, false);
+        let first = clock.tick();
+        assert_eq!((first.time, first.steps, first.seeked), (0.0, 0, false));
+        for frame in 1..=8 {
+            let tick = clock.tick();
+            assert_eq!(tick.time, frame as f64 * 0.25);
+            assert_eq!((tick.step, tick.steps), (0.25, 1));
+        }
+        assert_eq!(clock.dropped_frames(), 0);
+    }
+
+    #[test]
+    fn rate_scales_the_step() {
+        let mut clock = Clock::new(FPS, false);
+        clock.tick();
+        clock.set_rate(2.0);
+        let tick = clock.tick();
+        assert_eq!((tick.time, tick.step, tick.steps), (0.5, 0.5, 2));
+        clock.set_rate(0.5);
+        let steps: Vec<usize> = (0..4).map(|_| clock.tick().steps).collect();
+        assert_eq!(steps, [0, 1, 0, 1]);
+        assert_eq!(clock.time(), 1.0);
+        // Negative rates would run backwards.
+        clock.set_rate(-1.0);
+        assert_eq!(clock.tick().time, 1.0);
+    }
+
+    #[test]
+    fn seek_while_paused() {
+        let mut clock = Clock::new(FPS, false);
+        clock.tick();
+        clock.tick();
+        clock.apply(Control::TogglePause);
+        let tick = clock.tick();
+        assert_eq!((tick.time, tick.step, tick.steps), (0.25, 0.0, 0));
+        clock.apply(Control::Seek(10.0));
+        let tick = clock.tick();
+        assert_eq!((tick.time, tick.steps, tick.seeked), (10.0, 0, true));
+        // Still paused after the jump.
+        let tick = clock.tick();
+        assert_eq!((tick.time, tick.steps, tick.seeked), (10.0, 0, false));
+        clock.apply(Control::SeekBy(-20.0));
+        assert_eq!(clock.tick().time, 0.0);
+        clock.apply(Control::TogglePause);
+        assert_eq!(clock.tick().time, 0.25);
+    }
+
+    /// Frames of 15.625ms, in exact steps.
+    const REAL_TIME_FPS: f64 = 64.0;
+
+    #[test]
+    fn real_time_drops_late_frames() {
+        let mut clock = Clock::new(REAL_TIME_FPS, true);
+        clock.tick();
+        std::thread::sleep(Duration::from_millis(100));
+        let tick = clock.tick();
+        assert!(tick.steps >= 6, "caught up {} frames", tick.steps);
+        assert_eq!(tick.time, tick.steps as f64 / REAL_TIME_FPS);
+        assert_eq!(clock.dropped_frames(), tick.steps as u64 - 1);
+    }
+
+    #[test]
+    fn real_time_keeps_late_frames() {
+        let mut clock = Clock::new(REAL_TIME_FPS, true);
+        clock.set_keep_late(true);
+        clock.tick();
+        std::thread::sleep(Duration::from_millis(100));
+        let tick = clock.tick();
+        assert_eq!((tick.time, tick.steps), (1.0 / REAL_TIME_FPS, 1));
+        // Due a frame time after it was shown, not after it was due.
+        let started = Instant::now();
+        assert_eq!(clock.tick().steps, 1);
+        assert!(started.elapsed() >= Duration::from_millis(10));
+        assert_eq!(clock.dropped_frames(), 0);
+    }
+
+    #[test]
+    fn switching_to_real_time_keeps_the_time() {
+        let mut clock = Clock::new(REAL_TIME_FPS, false);
+        clock.set_rate(2.0);
+        for _ in 0..10 {
+            clock.tick();
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        clock.set_real_time(true);
+        // Only the time since the switch counts, not the time since the last frame.
+        let tick = clock.tick();
+        assert_eq!(tick.steps, 2);
+        // 9 frames after the first one at rate 2, and this one.
+        assert_eq!(tick.time, 20.0 / REAL_TIME_FPS);
+        assert_eq!(clock.dropped_frames(), 0);
+    }
+}
@@ -0,0 +1,797 @@
+use std::str::FromStr;
+
+use rs_piano_midi::canvas::BlendMode;
+
+pub const USAGE: &str = "\
+usage: sketch [options] [file.mid]
+
+Plays `file.mid`, or the song baked into `song.rs` without one.
+
+options, also accepted as `key = value` lines in a --config file:
+  --config <file>         read options from a file, options after it override it
+  --width <px>            frame width [640]
+  --height <px>           frame height [480]
+  --size <size>           width and height as WxH, or 480p, 720p, 1080p, 4k,
+                          vertical (1080x1920)
+  --fps <n>               frames per second [30]
+  --rate <x>              playback rate, 1 is normal speed [1]
+  --start <seconds>       start playing or recording at this time [0]
+  --end <seconds>         stop at this time
+  --loop                  go back to --start at --end, or at the end of the song
+  --view <seconds>        how far ahead notes are visible [0.4]
+  --slope <px>            how far the note trails lean sideways at 640x480 [30]
+  --palette <#rrggbb,..>  note colors from low to high keys
+  --background <#rrggbb>  color behind the notes, or none for transparent [none]
+  --trail <seconds>       keep the previous frames, fading towards the background
+                          by half every <seconds>, 0 starts every frame empty [0]
+  --motion-blur <n>       average <n> moments within each frame, 1 for none [1]
+  --antialias <bool>      smooth edges on lines, curves and circles [true]
+  --stroke <px>           width of lines at 640x480 [1]
+  --note-width <px>       width of the note bars at 640x480 [3]
+  --droplet-size <px>     radius of the droplets at 640x480 [3]
+  --note-blend <mode>     how note bars are drawn over what's below them: replace,
+                          over, blend, add, screen, multiply or lighten [over]
+  --droplet-blend <mode>  how droplets are drawn, add makes them glow [add]
+  --effects <list>        post-processing applied to the frames in order, comma
+                          separated: bloom, vignette, grain, aberration, scanlines,
+                          grade or none [none]
+  --bloom <x>             how strongly bright parts glow, adds bloom to the effects
+                          unless 0 [1]
+  --bloom-radius <px>     how far the glow reaches at 640x480 [8]
+  --bloom-threshold <x>   brightness from 0 to 1 where the glow starts [0.3]
+  --vignette <x>          how much darker the corners get from 0 to 1, adds the
+                          vignette unless 0 [0.5]
+  --grain <x>             how strong the film grain is from 0 to 1, adds grain
+                          unless 0 [0.08]
+  --aberration <px>       how far red and blue drift apart in the corners at
+                          640x480, adds chromatic aberration unless 0 [2]
+  --scanlines <x>         how much darker every other line gets from 0 to 1, adds
+                          scanlines unless 0 [0.3]
+  --grade <#rrggbb,..>    colors from dark to bright that the brightness maps to,
+                          adds color grading [black, then --palette]
+  --grade-mix <x>         how much of the graded color replaces the drawn one [1]
+  --sinks <list>          where frames go, comma separated: display, ffmpeg, png,
+                          svg, raw, y4m, gif or null [display]
+  --image-sink <file>     memory mapped file the display sink writes [/tmp/imagesink]
+  --record                add ffmpeg to the sinks
+  --output <file>         where ffmpeg writes the recording [video.mp4]
+  --raw <file>            add a sink writing the raw RGBA frames back to back, and
+                          their size and frame rate to <file>.txt
+  --y4m <file>            add a sink writing uncompressed YUV4MPEG2 video, no
+                          ffmpeg needed
+  --offline               record as fast as possible without a preview, then exit
+  --tail <seconds>        how long to keep recording after the last note [2]
+  --gif <file>            add a sink writing an animated GIF, best with --start
+                          and --end around a short clip
+  --gif-fps <n>           frames per second kept in the GIF [15]
+  --gif-loop <n>          times the GIF plays, 0 for forever [0]
+  --png-dir <dir>         add a sink writing every frame as a numbered RGBA PNG
+  --png-start <frame>     first frame written as PNG, counted from --start [0]
+  --png-end <frame>       last frame written as PNG
+  --svg-dir <dir>         add a sink writing what every frame draws as a numbered
+                          SVG, without trails and effects
+  --svg-start <frame>     first frame written as SVG, counted from --start [0]
+  --svg-end <frame>       last frame written as SVG
+  --audio                 play the song through the built-in synth while drawing
+  --wav <file>            only render the song to a WAV file
+  --sample-rate <hz>      sample rate of the synth [48000]
+  --audio-file <file>     mux this into the recording instead of the synth
+  --audio-offset <s>      seconds of the audio to skip, or to delay it when negative";
+
+const PALETTE: [&str; 5] = ["#160729", "#171856", "#243771", "#416e8f", "#dbf3f1"];
+/// Sub-frames are summed up in 16 bits per channel.
+const MAX_MOTION_BLUR: usize = 256;
+/// Options that are switched on by their presence on the command line.
+const SWITCHES: [&str; 4] = ["record", "audio", "offline", "loop"];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub midi: Option<String>,
+    pub width: usize,
+    pub height: usize,
+    pub fps: f64,
+    pub rate: f64,
+    pub start: f32,
+    pub end: Option<f32>,
+    /// Go back to `start` at `end` in the preview.
+    pub looping: bool,
+    /// Seconds of future notes visible on screen.
+    pub view: f32,
+    pub slope: f32,
+    pub palette: Vec<[u8; 4]>,
+    /// Premultiplied, transparent black unless set.
+    pub background: [u8; 4],
+    /// Seconds for old frames to fade halfway to the background, 0 for no trails.
+    pub trail: f32,
+    /// Moments drawn and averaged into each frame.
+    pub motion_blur: usize,
+    pub antialias: bool,
+    /// Line width at 640x480.
+    pub stroke: f32,
+    pub note_width: f32,
+    /// Droplet radius at 640x480.
+    pub droplet_size: f32,
+    pub note_blend: BlendMode,
+    pub droplet_blend: BlendMode,
+    /// Post-processing in the order it's applied.
+    pub effects: Vec<EffectKind>,
+    pub glow: Glow,
+    /// How much darker the corners get, 0..=1.
+    pub vignette: f32,
+    /// Strength of the film grain, 0..=1.
+    pub grain: f32,
+    /// How far red and blue drift apart in the corners at 640x480.
+    pub aberration: f32,
+    /// How much darker the lines in between get, 0..=1.
+    pub scanlines: f32,
+    /// Colors from dark to bright to grade with, empty for black and the palette.
+    pub grade: Vec<[u8; 4]>,
+    pub grade_mix: f32,
+    pub sinks: Vec<SinkKind>,
+    /// Memory mapped file the display sink shares the frames through.
+    pub image_sink: String,
+    pub output: String,
+    pub raw: Option<String>,
+    pub y4m: Option<String>,
+    /// Render every frame straight into the recording instead of in real time.
+    pub offline: bool,
+    /// Seconds recorded after the last note ends, for the droplets to settle.
+    pub tail: f32,
+    pub png: ImageSequence,
+    pub svg: ImageSequence,
+    pub gif: GifClip,
+    /// Play the song through the built-in synth while drawing.
+    pub audio: bool,
+    /// Render the song to this WAV file instead of drawing it.
+    pub wav: Option<String>,
+    pub soundtrack: Soundtrack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SinkKind {
+    Display,
+    Ffmpeg,
+    Png,
+    Svg,
+    Raw,
+    Y4m,
+    Gif,
+    Null,
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "display" => Ok(SinkKind::Display),
+            "ffmpeg" => Ok(SinkKind::Ffmpeg),
+            "png" => Ok(SinkKind::Png),
+            "svg" => Ok(SinkKind::Svg),
+            "raw" => Ok(SinkKind::Raw),
+            "y4m" => Ok(SinkKind::Y4m),
+            "gif" => Ok(SinkKind::Gif),
+            "null" => Ok(SinkKind::Null),
+            _ => Err(format!("unknown sink `{name}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EffectKind {
+    Bloom,
+    Vignette,
+    Grain,
+    Aberration,
+    Scanlines,
+    Grade,
+}
+
+impl FromStr for EffectKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "bloom" => Ok(EffectKind::Bloom),
+            "vignette" => Ok(EffectKind::Vignette),
+            "grain" => Ok(EffectKind::Grain),
+            "aberration" => Ok(EffectKind::Aberration),
+            "scanlines" => Ok(EffectKind::Scanlines),
+            "grade" => Ok(EffectKind::Grade),
+            _ => Err(format!("unknown effect `{name}`")),
+        }
+    }
+}
+
+/// Frames written as numbered images, e.g. PNGs for compositing in a video editor.
+#[derive(Debug, Clone, Default)]
+pub struct ImageSequence {
+    pub dir: Option<String>,
+    /// Frames counted from the first one drawn, `end` included.
+    pub start: usize,
+    pub end: Option<usize>,
+}
+
+/// An animated GIF of the frames.
+#[derive(Debug, Clone)]
+pub struct GifClip {
+    pub file: Option<String>,
+    /// Frames per second kept, the others are skipped.
+    pub fps: f64,
+    /// Times the animation plays, 0 for forever.
+    pub plays: u16,
+}
+
+/// Bloom over bright parts of the frames.
+#[derive(Debug, Clone)]
+pub struct Glow {
+    pub strength: f32,
+    /// Reach of the blur at 640x480.
+    pub radius: f32,
+    /// Luma in 0..1 where the glow starts.
+    pub threshold: f32,
+}
+
+/// Audio for the recording and the live playback.
+#[derive(Debug, Clone)]
+pub struct Soundtrack {
+    /// Muxed into the recording instead of the synthesized notes.
+    pub file: Option<String>,
+    pub sample_rate: u32,
+    /// Seconds of the audio to skip, e.g. a file's leading silence, or to delay it by
+    /// when negative.
+    pub offset: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            midi: None,
+            width: 640,
+            height: 480,
+            fps: 30.0,
+            rate: 1.0,
+            start: 0.0,
+            end: None,
+            looping: false,
+            view: 0.4,
+            slope: 30.0,
+            palette: PALETTE.iter().map(|hex| hex_to_rgb(hex).unwrap()).collect(),
+            background: [0; 4],
+            trail: 0.0,
+            motion_blur: 1,
+            antialias: true,
+            stroke: 1.0,
+            note_width: 3.0,
+            droplet_size: 3.0,
+            note_blend: BlendMode::Over,
+            droplet_blend: BlendMode::Add,
+            effects: Vec::new(),
+            glow: Glow {
+                strength: 1.0,
+                radius: 8.0,
+                threshold: 0.3,
+            },
+            vignette: 0.5,
+            grain: 0.08,
+            aberration: 2.0,
+            scanlines: 0.3,
+            grade: Vec::new(),
+            grade_mix: 1.0,
+            sinks: vec![SinkKind::Display],
+            image_sink: "/tmp/imagesink".into(),
+            output: "video.mp4".into(),
+            raw: None,
+            y4m: None,
+            offline: false,
+            tail: 2.0,
+            png: ImageSequence::default(),
+            svg: ImageSequence::default(),
+            gif: GifClip {
+                file: None,
+                fps: 15.0,
+                plays: 0,
+            },
+            audio: false,
+            wav: None,
+            soundtrack: Soundtrack {
+                file: None,
+                sample_rate: 48000,
+                offset: 0.0,
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Options are applied in order, so later ones win.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(key) = arg.strip_prefix("--") else {
+                config.midi = Some(arg);
+                continue;
+            };
+            if SWITCHES.contains(&key) {
+                config.set(key, "true")?;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("--{key} needs a value"))?;
+            if key == "config" {
+                config.load(&value)?;
+            } else {
+                config.set(key, &value)?;
+            }
+        }
+        // There is nothing to show an offline render on, it's recorded unless told otherwise.
+        if config.offline {
+            config.sinks.retain(|sink| *sink != SinkKind::Display);
+            if config.sinks.is_empty() {
+                config.sinks.push(SinkKind::Ffmpeg);
+            }
+        }
+        if config.sinks.contains(&SinkKind::Png) && config.png.dir.is_none() {
+            return Err("the png sink needs --png-dir".into());
+        }
+        if config.sinks.contains(&SinkKind::Svg) && config.svg.dir.is_none() {
+            return Err("the svg sink needs --svg-dir".into());
+        }
+        if config.sinks.contains(&SinkKind::Raw) && config.raw.is_none() {
+            return Err("the raw sink needs --raw".into());
+        }
+        if config.sinks.contains(&SinkKind::Gif) && config.gif.file.is_none() {
+            return Err("the gif sink needs --gif".into());
+        }
+        if config.sinks.contains(&SinkKind::Y4m) && config.y4m.is_none() {
+            return Err("the y4m sink needs --y4m".into());
+        }
+        // The soundtrack is muxed in once at normal speed, the video has to play it so.
+        if config.sinks.contains(&SinkKind::Ffmpeg) {
+            if config.rate != 1.0 {
+                return Err("ffmpeg records at rate 1, --rate can't be changed with it".into());
+            }
+            if config.looping && !config.offline {
+                return Err("ffmpeg records the song once, --loop can't be used with it".into());
+            }
+        }
+        if config.end.is_some_and(|end| end <= config.start) {
+            return Err("end has to be after start".into());
+        }
+        if config.png.end.is_some_and(|end| end < config.png.start) {
+            return Err("png-end can't be before png-start".into());
+        }
+        if config.svg.end.is_some_and(|end| end < config.svg.start) {
+            return Err("svg-end can't be before svg-start".into());
+        }
+        Ok(config)
+    }
+
+    /// Reads `key = value` lines, blank lines and lines starting with `#` are skipped.
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |err| format!("{path}:{}: {err}", line_idx + 1);
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`".into()))?;
+            self.set(key.trim(), value.trim()).map_err(error)?;
+        }
+        Ok(())
+    }
+
+    pub fn frame_time(&self) -> f64 {
+        1.0 / self.fps
+    }
+
+    /// Whether any sink keeps the frames as a video or images, which need every frame in
+    /// order at one frame time apart.
+    pub fn recording(&self) -> bool {
+        self.sinks
+            .iter()
+            .any(|sink| !matches!(sink, SinkKind::Display | SinkKind::Null))
+    }
+
+    /// How much of its difference to the background a pixel keeps from one frame to the
+    /// next, 0 without trails.
+    pub fn trail_keep(&self) -> f32 {
+        if self.trail > 0.0 {
+            0.5f32.powf(self.frame_time() as f32 / self.trail)
+        } else {
+            0.0
+        }
+    }
+
+    /// How much bigger than at 640x480 things are drawn, so every resolution looks alike.
+    pub fn scale(&self) -> f32 {
+        (self.width as f32 / 640.0).min(self.height as f32 / 480.0)
+    }
+
+    /// `stroke` at the current resolution.
+    pub fn stroke(&self) -> f32 {
+        self.stroke * self.scale()
+    }
+
+    /// `note_width` at the current resolution.
+    pub fn note_width(&self) -> f32 {
+        self.note_width * self.scale()
+    }
+
+    /// `glow.radius` at the current resolution.
+    pub fn bloom_radius(&self) -> f32 {
+        self.glow.radius * self.scale()
+    }
+
+    /// `aberration` at the current resolution.
+    pub fn aberration(&self) -> f32 {
+        self.aberration * self.scale()
+    }
+
+    /// `slope` at the current resolution.
+    pub fn slope(&self) -> f32 {
+        self.slope * self.scale()
+    }
+
+    fn add_sink(&mut self, sink: SinkKind) {
+        if !self.sinks.contains(&sink) {
+            self.sinks.push(sink);
+        }
+    }
+
+    fn add_effect(&mut self, effect: EffectKind) {
+        if !self.effects.contains(&effect) {
+            self.effects.push(effect);
+        }
+    }
+
+    /// Parses the amount of an effect, adding the effect unless it's 0 and removing it
+    /// otherwise.
+    fn effect_amount(&mut self, effect: EffectKind, key: &str, value: &str) -> Result<f32, String> {
+        let amount: f32 = parse(key, value)?;
+        if amount < 0.0 {
+            return Err(format!("{key} can't be negative, got `{value}`"));
+        }
+        if amount > 0.0 {
+            self.add_effect(effect);
+        } else {
+            self.effects.retain(|kind| *kind != effect);
+        }
+        Ok(amount)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "midi" => self.midi = Some(value.into()),
+            "width" => self.width = parse_positive(key, value)?,
+            "height" => self.height = parse_positive(key, value)?,
+            "size" => (self.width, self.height) = parse_size(value)?,
+            "fps" => self.fps = parse_positive(key, value)?,
+            "rate" => self.rate = parse_positive(key, value)?,
+            "start" => {
+                self.start = parse(key, value)?;
+                if self.start < 0.0 {
+                    return Err(format!("start can't be negative, got `{value}`"));
+                }
+            }
+            "end" => self.end = Some(parse_positive(key, value)?),
+            "loop" => self.looping = parse(key, value)?,
+            "view" => self.view = parse_positive(key, value)?,
+            "slope" => self.slope = parse(key, value)?,
+            "palette" => {
+                self.palette = value
+                    .split(',')
+                    .map(|hex| hex_to_rgb(hex.trim()))
+                    .collect::<Result<_, _>>()?;
+                if self.palette.is_empty() {
+                    return Err("palette needs at least one color".into());
+                }
+            }
+            "background" => {
+                self.background = match value {
+                    "none" => [0; 4],
+                    hex => hex_to_rgb(hex)?,
+                }
+            }
+            "trail" => {
+                self.trail = parse(key, value)?;
+                if self.trail < 0.0 {
+                    return Err(format!("trail can't be negative, got `{value}`"));
+                }
+            }
+            "motion-blur" => {
+                self.motion_blur = parse_positive(key, value)?;
+                if self.motion_blur > MAX_MOTION_BLUR {
+                    return Err(format!("motion-blur is at most {MAX_MOTION_BLUR}"));
+                }
+            }
+            "antialias" => self.antialias = parse(key, value)?,
+            "stroke" => self.stroke = parse_positive(key, value)?,
+            "note-width" => self.note_width = parse_positive(key, value)?,
+            "droplet-size" => self.droplet_size = parse_positive(key, value)?,
+            "note-blend" => self.note_blend = value.parse()?,
+            "droplet-blend" => self.droplet_blend = value.parse()?,
+            "effects" => {
+                self.effects = match value {
+                    "none" => Vec::new(),
+                    list => list
+                        .split(',')
+                        .map(|name| name.trim().parse())
+                        .collect::<Result<_, _>>()?,
+                }
+            }
+            "bloom" => self.glow.strength = self.effect_amount(EffectKind::Bloom, key, value)?,
+            "bloom-radius" => self.glow.radius = parse_positive(key, value)?,
+            "bloom-threshold" => {
+                self.glow.threshold = parse(key, value)?;
+                if !(0.0..1.0).contains(&self.glow.threshold) {
+                    return Err(format!(
+                        "bloom-threshold is from 0 to below 1, got `{value}`"
+                    ));
+                }
+            }
+            "vignette" => self.vignette = self.effect_amount(EffectKind::Vignette, key, value)?,
+            "grain" => self.grain = self.effect_amount(EffectKind::Grain, key, value)?,
+            "aberration" => {
+                self.aberration = self.effect_amount(EffectKind::Aberration, key, value)?
+            }
+            "scanlines" => {
+                self.scanlines = self.effect_amount(EffectKind::Scanlines, key, value)?
+            }
+            "grade" => {
+                self.grade = value
+                    .split(',')
+                    .map(|hex| hex_to_rgb(hex.trim()))
+                    .collect::<Result<_, _>>()?;
+                self.add_effect(EffectKind::Grade);
+            }
+            "grade-mix" => {
+                self.grade_mix = parse(key, value)?;
+                if !(0.0..=1.0).contains(&self.grade_mix) {
+                    return Err(format!("grade-mix is from 0 to 1, got `{value}`"));
+                }
+            }
+            "sinks" => {
+                self.sinks = value
+                    .split(',')
+                    .map(|name| name.trim().parse())
+                    .collect::<Result<_, _>>()?;
+            }
+            "image-sink" => self.image_sink = value.into(),
+            "record" => {
+                if parse(key, value)? {
+                    self.add_sink(SinkKind::Ffmpeg);
+                } else {
+                    self.sinks.retain(|sink| *sink != SinkKind::Ffmpeg);
+                }
+            }
+            "output" => self.output = value.into(),
+            "raw" => {
+                self.raw = Some(value.into());
+                self.add_sink(SinkKind::Raw);
+            }
+            "y4m" => {
+                self.y4m = Some(value.into());
+                self.add_sink(SinkKind::Y4m);
+            }
+            "offline" => self.offline = parse(key, value)?,
+            "tail" => {
+                self.tail = parse(key, value)?;
+                if self.tail < 0.0 {
+                    return Err(format!("tail can't be negative, got `{value}`"));
+                }
+            }
+            "png-dir" => {
+                self.png.dir = Some(value.into());
+                self.add_sink(SinkKind::Png);
+            }
+            "gif" => {
+                self.gif.file = Some(value.into());
+                self.add_sink(SinkKind::Gif);
+            }
+            "gif-fps" => self.gif.fps = parse_positive(key, value)?,
+            "gif-loop" => self.gif.plays = parse(key, value)?,
+            "png-start" => self.png.start = parse(key, value)?,
+            "png-end" => self.png.end = Some(parse(key, value)?),
+            "svg-dir" => {
+                self.svg.dir = Some(value.into());
+                self.add_sink(SinkKind::Svg);
+            }
+            "svg-start" => self.svg.start = parse(key, value)?,
+            "svg-end" => self.svg.end = Some(parse(key, value)?),
+            "audio" => self.audio = parse(key, value)?,
+            "wav" => self.wav = Some(value.into()),
+            "sample-rate" => self.soundtrack.sample_rate = parse_positive(key, value)?,
+            "audio-file" => self.soundtrack.file = Some(value.into()),
+            "audio-offset" => self.soundtrack.offset = parse(key, value)?,
+            _ => return Err(format!("unknown option `{key}`")),
+        }
+        Ok(())
+    }
+}
+
+fn parse_size(value: &str) -> Result<(usize, usize), String> {
+    match value {
+        "480p" => return Ok((640, 480)),
+        "720p" => return Ok((1280, 720)),
+        "1080p" => return Ok((1920, 1080)),
+        "4k" => return Ok((3840, 2160)),
+        "vertical" => return Ok((1080, 1920)),
+        _ => (),
+    }
+    let (width, height) = value
+        .split_once('x')
+        .ok_or_else(|| format!("invalid size `{value}`, expected WxH or a preset"))?;
+    Ok((
+        parse_positive("width", width)?,
+        parse_positive("height", height)?,
+    ))
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value `{value}` for {key}"))
+}
+
+fn parse_positive<T: FromStr + PartialOrd + Default>(key: &str, value: &str) -> Result<T, String> {
+    let parsed: T = parse(key, value)?;
+    if parsed > T::default() {
+        Ok(parsed)
+    } else {
+        Err(format!("{key} has to be positive, got `{value}`"))
+    }
+}
+
+pub fn hex_to_rgb(hex: &str) -> Result<[u8; 4], String> {
+    let digits = hex.trim_start_matches('#');
+    let channel = |range| {
+        digits
+            .get(range)
+            .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+            .ok_or_else(|| format!("invalid color `{hex}`, expected #rrggbb"))
+    };
+    if digits.len() != 6 {
+        return Err(format!("invalid color `{hex}`, expected #rrggbb"));
+    }
+    Ok([channel(0..2)?, channel(2..4)?, channel(4..6)?, 255])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(args: &str) -> Result<Config, String> {
+        Config::from_args(args.split_whitespace().map(String::from))
+    }
+
+    /// Writes `text` to a file of its own for `--config`.
+    fn config_file(name: &str, text: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("sketch-test-{}-{name}.conf", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn switches_take_no_value() {
+        let config = from("--record --audio song.mid --fps 60").unwrap();
+        assert_eq!(config.sinks, [SinkKind::Display, SinkKind::Ffmpeg]);
+        assert!(config.audio);
+        assert_eq!(config.midi.as_deref(), Some("song.mid"));
+        assert_eq!(config.fps, 60.0);
+    }
+
+    #[test]
+    fn options_need_a_value() {
+        assert_eq!(from("--fps").unwrap_err(), "--fps needs a value");
+        // The next option isn't taken for one.
+        assert!(from("--width --record").is_err());
+    }
+
+    #[test]
+    fn later_options_win() {
+        let config = from("--size 720p --width 800 --effects bloom --bloom 0").unwrap();
+        assert_eq!((config.width, config.height), (800, 720));
+        assert!(config.effects.is_empty());
+    }
+
+    #[test]
+    fn offline_records_instead_of_displaying() {
+        let config = from("--offline").unwrap();
+        assert_eq!(config.sinks, [SinkKind::Ffmpeg]);
+        let config = from("--offline --y4m out.y4m").unwrap();
+        assert_eq!(config.sinks, [SinkKind::Y4m]);
+    }
+
+    #[test]
+    fn config_file_values() {
+        let path = config_file(
+            "values",
+            "# a comment\n\
+             \n\
+             fps = 24\n\
+             record = true\n\
+             loop = false\n\
+             antialias = false\n\
+             palette = #ff0000, #00ff00\n",
+        );
+        let config = from(&format!("--loop --config {path} --fps 50")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.fps, 50.0);
+        assert!(config.sinks.contains(&SinkKind::Ffmpeg));
+        assert!(!config.looping);
+        assert!(!config.antialias);
+        assert_eq!(config.palette, [[255, 0, 0, 255], [0, 255, 0, 255]]);
+    }
+
+    #[test]
+    fn config_file_errors_name_the_line() {
+        let path = config_file("errors", "fps = 24\nloop = yes\n");
+        let err = from(&format!("--config {path}")).unwrap_err();
+        assert_eq!(err, format!("{path}:2: invalid value `yes` for loop"));
+        std::fs::write(&path, "record\n").unwrap();
+        let err = from(&format!("--config {path}")).unwrap_err();
+        assert_eq!(err, format!("{path}:1: expected `key = value`"));
+        std::fs::write(&path, "colour = red\n").unwrap();
+        let err = from(&format!("--config {path}")).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err, format!("{path}:1: unknown option `colour`"));
+    }
+
+    #[test]
+    fn unknown_options() {
+        assert_eq!(from("--colour red").unwrap_err(), "unknown option `colour`");
+        assert_eq!(from("--sinks tv").unwrap_err(), "unknown sink `tv`");
+        assert_eq!(from("--effects blur").unwrap_err(), "unknown effect `blur`");
+        assert_eq!(
+            from("--note-blend mix").unwrap_err(),
+            "unknown blend mode `mix`"
+        );
+    }
+
+    #[test]
+    fn rejected_values() {
+        for args in [
+            "--fps 0",
+            "--fps -30",
+            "--fps fast",
+            "--width 0",
+            "--size 640",
+            "--start -1",
+            "--start 5 --end 5",
+            "--trail -1",
+            "--motion-blur 0",
+            "--motion-blur 257",
+            "--bloom -1",
+            "--bloom-threshold 1",
+            "--grade-mix 1.5",
+            "--palette #ff00",
+            "--background red",
+            "--record --rate 2",
+            "--record --loop",
+            "--sinks png",
+            "--sinks gif",
+            "--png-dir frames --png-start 10 --png-end 5",
+        ] {
+            assert!(from(args).is_err(), "`{args}` was accepted");
+        }
+        // Recorded offline, the song only plays once anyway.
+        assert!(from("--offline --loop").is_ok());
+        assert!(from("--y4m out.y4m --rate 2").is_ok());
+        assert!(from(&format!("--motion-blur {MAX_MOTION_BLUR}")).is_ok());
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(hex_to_rgb("#0a10ff"), Ok([10, 16, 255, 255]));
+        assert_eq!(hex_to_rgb("0a10ff"), Ok([10, 16, 255, 255]));
+        assert!(hex_to_rgb("#0a10fg").is_err());
+        assert!(hex_to_rgb("#0a10ff00").is_err());
+    }
+}
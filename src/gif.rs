@@ -0,0 +1,306 @@
+use std::io::{self, Write};
+
+/// LZW codes are at most 12 bits.
+const MAX_CODES: usize = 4096;
+
+/// Writes an animated GIF89a one palette-indexed frame at a time, all frames sharing
+/// one global palette.
+pub struct Encoder<W: Write> {
+    out: W,
+    width: u16,
+    height: u16,
+    /// Bits per pixel index, at least 2 as LZW needs.
+    min_code_size: u8,
+}
+
+impl<W: Write> Encoder<W> {
+    /// `repeat` is how often the animation plays again, `Some(0)` for forever and `None`
+    /// to play it once. The palette holds up to 256 colors.
+    pub fn new(
+        mut out: W,
+        width: u16,
+        height: u16,
+        palette: &[[u8; 3]],
+        repeat: Option<u16>,
+    ) -> io::Result<Self> {
+        if palette.is_empty() || palette.len() > 256 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a gif palette has 1 to 256 colors",
+            ));
+        }
+        // The color table has a power of two entries, from 2 up.
+        let table_bits = (palette.len().next_power_of_two().trailing_zeros() as u8).max(1);
+        out.write_all(b"GIF89a")?;
+        out.write_all(&width.to_le_bytes())?;
+        out.write_all(&height.to_le_bytes())?;
+        // Global color table, 8 bits per channel, background index 0, square pixels.
+        out.write_all(&[0x80 | 0x70 | (table_bits - 1), 0, 0])?;
+        for idx in 0..1 << table_bits {
+            out.write_all(palette.get(idx).unwrap_or(&[0, 0, 0]))?;
+        }
+        if let Some(repeat) = repeat {
+            out.write_all(&[0x21, 0xff, 11])?;
+            out.write_all(b"NETSCAPE2.0")?;
+            out.write_all(&[3, 1])?;
+            out.write_all(&repeat.to_le_bytes())?;
+            out.write_all(&[0])?;
+        }
+        Ok(Self {
+            out,
+            width,
+            height,
+            min_code_size: table_bits.max(2),
+        })
+    }
+
+    /// `pixels` holds a palette index per pixel, rows top to bottom. `delay` is in
+    /// hundredths of a second.
+    pub fn write_frame(&mut self, pixels: &[u8], delay: u16) -> io::Result<()> {
+        assert_eq!(
+            pixels.len(),
+            self.width as usize * self.height as usize,
+            "frame size doesn't match"
+        );
+        // Graphic control extension: no disposal, no transparency.
+        self.out.write_all(&[0x21, 0xf9, 4, 0])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0, 0])?;
+        // Image descriptor covering the whole screen, no local color table.
+        self.out.write_all(&[0x2c, 0, 0, 0, 0])?;
+        self.out.write_all(&self.width.to_le_bytes())?;
+        self.out.write_all(&self.height.to_le_bytes())?;
+        self.out.write_all(&[0])?;
+
+        self.out.write_all(&[self.min_code_size])?;
+        let data = lzw(pixels, self.min_code_size);
+        // Data goes in sub-blocks of up to 255 bytes, ended by an empty one.
+        for block in data.chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])
+    }
+
+    /// Writes the trailer and hands back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0x3b])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Variable width LZW as GIF uses it, the table restarts with a clear code when full.
+fn lzw(pixels: &[u8], min_code_size: u8) -> Vec<u8> {
+    let symbols = 1usize << min_code_size;
+    let clear = symbols as u16;
+    let end = clear + 1;
+    // `table[code * symbols + pixel]` is the code for `code`'s string plus `pixel`, 0 if
+    // there is none yet. 0 is never a code for a longer string, it's a single pixel.
+    let mut table = vec![0u16; MAX_CODES * symbols];
+    let mut next_code = end + 1;
+    let mut code_size = min_code_size as u32 + 1;
+
+    let mut bits = BitWriter::default();
+    bits.write(clear as u32, code_size);
+    let Some((&first, rest)) = pixels.split_first() else {
+        bits.write(end as u32, code_size);
+        return bits.finish();
+    };
+    let mut current = first as u16;
+    for &pixel in rest {
+        let entry = current as usize * symbols + pixel as usize;
+        if table[entry] != 0 {
+            current = table[entry];
+            continue;
+        }
+        bits.write(current as u32, code_size);
+        if (next_code as usize) < MAX_CODES {
+            // The decoder adds its entries a code behind, it only needs the wider codes
+            // once it has added the one that doesn't fit.
+            if next_code as u32 == 1 << code_size {
+                code_size += 1;
+            }
+            table[entry] = next_code;
+            next_code += 1;
+        } else {
+            bits.write(clear as u32, code_size);
+            table.fill(0);
+            next_code = end + 1;
+            code_size = min_code_size as u32 + 1;
+        }
+        current = pixel as u16;
+    }
+    bits.write(current as u32, code_size);
+    bits.write(end as u32, code_size);
+    bits.finish()
+}
+
+/// Packs codes least significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u32,
+    filled: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.current |= value << self.filled;
+        self.filled += count;
+        while self.filled >= 8 {
+            self.bytes.push(self.current as u8);
+            self.current >>= 8;
+            self.filled -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.filled > 0 {
+            self.bytes.push(self.current as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What decoding a frame found besides the pixels.
+    struct Decoded {
+        pixels: Vec<u8>,
+        /// Clear codes, including the one at the start.
+        clears: usize,
+        widest_code: u32,
+    }
+
+    /// Decodes LZW data the way GIF readers do, growing the codes a code late.
+    fn decode(data: &[u8], min_code_size: u8) -> Decoded {
+        let clear = 1usize << min_code_size;
+        let end = clear + 1;
+        let reset = || -> Vec<Vec<u8>> { (0..clear + 2).map(|code| vec![code as u8]).collect() };
+        let mut table = reset();
+        let mut code_size = min_code_size as u32 + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut decoded = Decoded {
+            pixels: Vec::new(),
+            clears: 0,
+            widest_code: code_size,
+        };
+        let mut pos = 0;
+        loop {
+            let code = (0..code_size).fold(0, |code, bit| {
+                let byte = data[(pos + bit as usize) / 8];
+                code | ((byte >> ((pos + bit as usize) % 8) & 1) as usize) << bit
+            });
+            pos += code_size as usize;
+            decoded.widest_code = decoded.widest_code.max(code_size);
+            if code == clear {
+                table = reset();
+                code_size = min_code_size as u32 + 1;
+                previous = None;
+                decoded.clears += 1;
+                continue;
+            }
+            if code == end {
+                break;
+            }
+            let entry = match (table.get(code), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(previous)) if code == table.len() => {
+                    [previous.as_slice(), &previous[..1]].concat()
+                }
+                _ => panic!("code {code} not in the table yet"),
+            };
+            decoded.pixels.extend(&entry);
+            if let Some(previous) = previous {
+                if table.len() < MAX_CODES {
+                    table.push([previous.as_slice(), &entry[..1]].concat());
+                    if table.len() == 1 << code_size && code_size < 12 {
+                        code_size += 1;
+                    }
+                }
+            }
+            previous = Some(entry);
+        }
+        // Nothing but padding after the end code.
+        assert_eq!(data.len(), pos.div_ceil(8));
+        decoded
+    }
+
+    fn noise(len: usize, colors: u8, seed: u64) -> Vec<u8> {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        (0..len).map(|_| rng.u8(..colors)).collect()
+    }
+
+    fn round_trip(pixels: &[u8], min_code_size: u8) -> Decoded {
+        let decoded = decode(&lzw(pixels, min_code_size), min_code_size);
+        assert!(decoded.pixels == pixels, "pixels differ after decoding");
+        decoded
+    }
+
+    #[test]
+    fn empty_and_single_pixels() {
+        round_trip(&[], 2);
+        round_trip(&[3], 2);
+        round_trip(&[1, 1], 2);
+    }
+
+    #[test]
+    fn codes_grow_as_the_table_fills() {
+        // Enough new strings to need 6 bit codes, not enough to fill the table.
+        let decoded = round_trip(&noise(60, 4, 1), 2);
+        assert_eq!(decoded.clears, 1);
+        assert!(decoded.widest_code >= 5 && decoded.widest_code < 12);
+        // Runs build long strings, a code for each one a pixel longer.
+        round_trip(&[0; 5000], 2);
+    }
+
+    #[test]
+    fn table_clears_at_4096_codes() {
+        let decoded = round_trip(&noise(40_000, 4, 2), 2);
+        assert_eq!(decoded.widest_code, 12);
+        assert!(decoded.clears > 2);
+        let decoded = round_trip(&noise(40_000, 255, 3), 8);
+        assert!(decoded.clears > 2);
+    }
+
+    #[test]
+    fn frames_in_a_file() {
+        let (width, height) = (13u16, 7u16);
+        // One color still makes a table of two and takes 2 bit pixels.
+        let palette = [[200, 100, 50]];
+        let mut encoder = Encoder::new(Vec::new(), width, height, &palette, Some(0)).unwrap();
+        assert_eq!(encoder.min_code_size, 2);
+        let frames = [vec![0; 91], noise(91, 2, 4)];
+        for frame in &frames {
+            encoder.write_frame(frame, 4).unwrap();
+        }
+        let file = encoder.finish().unwrap();
+
+        assert_eq!(&file[..6], b"GIF89a");
+        assert_eq!(file[10] & 0x07, 0, "table of two colors");
+        assert_eq!(file[13..19], [200, 100, 50, 0, 0, 0]);
+        assert_eq!(*file.last().unwrap(), 0x3b);
+        // After the looping extension: control extension, descriptor, then the data.
+        let mut pos = 19 + 19;
+        for frame in &frames {
+            assert_eq!(file[pos..pos + 2], [0x21, 0xf9]);
+            pos += 8;
+            assert_eq!(file[pos], 0x2c);
+            pos += 10;
+            let min_code_size = file[pos];
+            pos += 1;
+            let mut data = Vec::new();
+            while file[pos] != 0 {
+                let len = file[pos] as usize;
+                data.extend(&file[pos + 1..pos + 1 + len]);
+                pos += 1 + len;
+            }
+            pos += 1;
+            assert!(decode(&data, min_code_size).pixels == *frame);
+        }
+        assert_eq!(pos, file.len() - 1);
+    }
+}
@@ -0,0 +1,8 @@
+pub mod canvas;
+pub mod gif;
+pub mod midi;
+pub mod png;
+pub mod raster;
+pub mod svg;
+pub mod synth;
+pub mod wav;
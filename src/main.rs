@@ -1,30 +1,80 @@
 use glam::Vec2;
 
 use std::f32::consts::PI;
-use std::io::Write;
+use std::io::{BufWriter, Write};
+use std::os::fd::AsRawFd;
 use std::process::{ChildStdin, Command, Stdio};
-
-const FPS: f64 = 30.0;
-const FRAME_TIME: f64 = 1.0 / FPS;
-// seconds of future notes visible on screen.
-const VIEW: f32 = 0.4;
-
-const RECORD: bool = false;
-const WIDTH: usize = 640;
-const HEIGHT: usize = 480;
-const PALETTE: [&'static str; 5] = ["#160729", "#171856", "#243771", "#416e8f", "#dbf3f1"];
-const SLOPE: f32 = 30.0;
-const SLOPE_ANGLE: f32 = SLOPE / 480.0;
-
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+// seconds of audio aplay keeps buffered, see `--buffer-time` in `Audio::new`.
+const AUDIO_BUFFER: f64 = 0.05;
+// bytes of samples the pipe to aplay may hold, on top of aplay's own buffer.
+const AUDIO_PIPE_SIZE: i32 = 4096;
+// droplets simulated at most after dropped frames or a pause, they are gone by then.
+const MAX_CATCH_UP_STEPS: usize = 60;
+// seconds simulated before a seek target, droplets land within about a second.
+const WARM_UP: f32 = 1.5;
+// `song::NOTES` has no velocities, play them all at full strength.
+const BAKED_VELOCITY: u8 = 127;
+
+mod clock;
+mod config;
+mod post;
+mod sink;
 mod song;
+use clock::{Clock, Control, Tick, CONTROLS};
+use config::{Config, EffectKind, SinkKind, Soundtrack, USAGE};
+use post::{Aberration, Bloom, Effect, Grade, Grain, Scanlines, Vignette};
+use rs_piano_midi::canvas::{Canvas, LineCap};
+use rs_piano_midi::midi::{Note, Song};
+use rs_piano_midi::synth::Synth;
+use rs_piano_midi::wav;
+use sink::{
+    AudioTrack, FfmpegSink, Frame, FrameSink, GifSink, ImageSink, NullSink, PngSink, RawSink,
+    SvgSink, Y4mSink,
+};
 use song::NOTES;
 
 fn main() {
-    let mut sketch = Sketch::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}\n\n{CONTROLS}");
+        return;
+    }
+    let config = match Config::from_args(args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}, see `sketch --help`");
+            std::process::exit(2);
+        }
+    };
+
+    let notes = match &config.midi {
+        Some(path) => match Song::load(path) {
+            Ok(song) => song.notes(),
+            Err(err) => {
+                eprintln!("{path}: {err}");
+                std::process::exit(1);
+            }
+        },
+        None => baked_notes(),
+    };
+    if let Some(path) = &config.wav {
+        if let Err(err) = render_wav(&notes, path, config.soundtrack.sample_rate, config.fps) {
+            eprintln!("{path}: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut sketch = Sketch::new(notes, config);
     sketch.run();
 }
 
+// per frame at 30 fps and 640x480, scaled with the frame rate and the resolution.
 const GRAVITY: Vec2 = Vec2 { x: 0.0, y: 1.0 };
+const EXPLOSION_SPEED: f32 = 15.0;
+const PHYSICS_FPS: f64 = 30.0;
 #[derive(Clone)]
 struct Particle {
     pos: Vec2,
@@ -42,65 +92,94 @@ impl Particle {
         }
     }
 
-    pub fn update(&mut self) {
+    pub fn update(&mut self, frame_time: f32, gravity: Vec2) {
         self.pos += self.vel;
-        self.vel += GRAVITY;
-        self.lifetime += FRAME_TIME as f32;
+        self.vel += gravity;
+        self.lifetime += frame_time;
     }
 }
 
 struct Particles {
     particles: Vec<Particle>,
     lines: Vec<(Vec2, Vec2)>,
+    /// y of the bottom edge, where notes land and droplets disappear.
+    floor: f32,
+    slope_angle: f32,
+    /// `GRAVITY` per frame at the current resolution and frame rate.
+    gravity: Vec2,
+    /// `EXPLOSION_SPEED` per frame at the current resolution and frame rate.
+    speed: f32,
+    /// Radius of a droplet in pixels.
+    size: f32,
 }
 
 impl Particles {
-    pub fn new() -> Self {
+    /// `scale` is the size of a 640x480 pixel at the current resolution.
+    pub fn new(floor: f32, slope_angle: f32, scale: f32, size: f32, fps: f64) -> Self {
+        // Frames at `PHYSICS_FPS` a frame lasts, velocities scale with it and the
+        // acceleration with its square.
+        let frames = (PHYSICS_FPS / fps) as f32;
         Self {
             particles: Vec::new(),
             lines: Vec::new(),
+            floor,
+            slope_angle,
+            gravity: GRAVITY * scale * frames * frames,
+            speed: EXPLOSION_SPEED * scale * frames,
+            size,
         }
     }
 
-    pub fn update(&mut self) {
+    pub fn update(&mut self, frame_time: f32) {
         for particle in &mut self.particles {
-            particle.update();
+            particle.update(frame_time, self.gravity);
         }
         let new: Vec<Particle> = self
             .particles
             .iter()
+            .filter(|particle| particle.pos.y < self.floor)
             .cloned()
-            .filter(|particle| !(particle.pos.y >= HEIGHT as f32))
             .collect();
         self.particles = new;
     }
 
-    pub fn draw(&mut self, canvas: &mut Canvas) {
+    /// Draws the droplets where they were `back` frames ago, the lines stay until
+    /// `clear_lines`.
+    pub fn draw(&self, canvas: &mut Canvas, back: f32) {
         canvas.select_color(2);
         for particle in &self.particles {
-            let (pos, next_pos) = (particle.pos, particle.vel + particle.pos);
-            let mut middle = (pos + next_pos) / 2.0;
-            middle -= GRAVITY;
-            canvas.draw_curve(pos, middle, next_pos);
+            // The last step moved them by their velocity before gravity changed it.
+            let step = particle.vel - self.gravity;
+            canvas.draw_dot(particle.pos - step * back, self.size);
         }
         for line in &self.lines {
             canvas.draw_line(line.0, line.1);
         }
+    }
+
+    pub fn clear_lines(&mut self) {
         self.lines.clear()
     }
 
-    fn particles_for_note(&mut self, pos: Vec2) {
-        let rest_y = HEIGHT as f32 - pos.y;
-        let end_x = rest_y * SLOPE_ANGLE + pos.x;
-        let end = Vec2::new(end_x, HEIGHT as f32);
+    pub fn clear(&mut self) {
+        self.particles.clear();
+        self.lines.clear();
+    }
+
+    fn particles_for_note(&mut self, pos: Vec2, velocity: u8) {
+        let rest_y = self.floor - pos.y;
+        let end_x = rest_y * self.slope_angle + pos.x;
+        let end = Vec2::new(end_x, self.floor);
         self.lines.push((pos, end));
-        self.spawn_explosion(end);
+        self.spawn_explosion(end, velocity as f32 / 127.0);
     }
 
-    fn spawn_explosion(&mut self, pos: Vec2) {
-        for i in 0..fastrand::usize(2..5) {
+    /// `strength` in 0..=1 scales both the amount and the speed of the droplets.
+    fn spawn_explosion(&mut self, pos: Vec2, strength: f32) {
+        let count = (fastrand::usize(2..5) as f32 * strength).ceil() as usize;
+        for _ in 0..count {
             let mut vel = Vec2::from_angle(-fastrand::f32() * PI);
-            vel *= fastrand::f32() * 15.0;
+            vel *= fastrand::f32() * self.speed * strength;
             let particle = Particle::new(pos, vel);
             self.particles.push(particle);
         }
@@ -108,322 +187,556 @@ impl Particles {
 }
 
 struct Sketch {
+    config: Config,
     canvas: Canvas,
-    ffmpeg: Option<ChildStdin>,
+    sinks: Vec<Box<dyn FrameSink>>,
+    /// Applied in order to a copy of each frame, the canvas keeps what was drawn.
+    effects: Vec<Box<dyn Effect>>,
+    /// The frame after the effects.
+    post: Vec<u8>,
+    audio: Option<Audio>,
+    clock: Clock,
+    controls: Option<Receiver<Control>>,
 
     frame: usize,
     time: f32,
-    visible_notes: Vec<(f32, u8)>,
+    /// Notes starting before this already spawned their droplets.
+    triggered_until: f32,
+    notes: Vec<Note>,
+    visible_notes: Vec<Note>,
+    longest_note: f32,
     note_lowest_highest: (u8, u8),
     droplets: Particles,
+    /// The last frame, faded, which the moments of a motion blurred frame are drawn on.
+    trail: Vec<u8>,
+    /// Sums of the moments of a motion blurred frame.
+    blur: Vec<u16>,
 }
 
 impl Sketch {
-    pub fn new() -> Self {
-        let ffmpeg = Self::ffmpeg();
-        let canvas = Self::canvas();
+    pub fn new(notes: Vec<Note>, config: Config) -> Self {
+        let sinks = Self::sinks(&notes, &config);
+        let effects = Self::effects(&config);
+        let mut canvas = Canvas::new(config.palette.clone(), config.width, config.height);
+        canvas.antialias = config.antialias;
+        canvas.stroke_width = config.stroke();
+        canvas.clear(config.background);
+        let mut audio = if config.audio && !config.offline {
+            Audio::new(&notes, config.soundtrack.sample_rate)
+        } else {
+            None
+        };
+        // With audio the writes to the player pace the frames instead.
+        let mut clock = Clock::new(config.fps, !config.offline && audio.is_none());
+        // Recordings need every frame, late or not.
+        clock.set_keep_late(config.recording());
+        if audio.is_some() && config.rate != 1.0 {
+            eprintln!("the audio only plays at rate 1");
+        } else {
+            clock.set_rate(config.rate);
+        }
+        if config.start > 0.0 {
+            clock.seek(config.start as f64);
+            if let Some(audio) = &mut audio {
+                audio.seek(config.start as f64);
+            }
+        }
+        // Pausing or seeking would leave gaps the recording doesn't have.
+        let recording = config.recording();
+        if recording && !config.offline {
+            eprintln!("recording, the controls are off");
+        }
+        let controls = (!config.offline && !recording).then(clock::read_controls);
+        let height = canvas.height() as f32;
+        let droplets = Particles::new(
+            height,
+            config.slope() / height,
+            config.scale(),
+            config.droplet_size * config.scale(),
+            config.fps,
+        );
 
-        let note_lowest_highest = note_find_lowest_highest();
+        let note_lowest_highest = note_find_lowest_highest(&notes);
+        let longest_note = notes.iter().map(|note| note.duration).fold(0.0, f32::max);
         Self {
+            config,
             canvas,
-            ffmpeg,
+            sinks,
+            effects,
+            post: Vec::new(),
+            audio,
+            clock,
+            controls,
             frame: 0,
             time: 0f32,
+            triggered_until: 0f32,
+            notes,
             visible_notes: Vec::new(),
+            longest_note,
             note_lowest_highest,
-            droplets: Particles::new(),
+            droplets,
+            trail: Vec::new(),
+            blur: Vec::new(),
         }
     }
 
     pub fn run(&mut self) {
+        if self.config.offline {
+            self.render();
+        } else {
+            self.preview();
+        }
+        for mut sink in std::mem::take(&mut self.sinks) {
+            if let Err(err) = sink.finish() {
+                eprintln!("{}: {err}", sink.name());
+            }
+        }
+    }
+
+    fn preview(&mut self) {
+        let end = match (self.config.end, self.config.looping) {
+            (Some(end), _) => Some(end),
+            (None, true) => Some(self.song_end() + self.config.tail),
+            (None, false) => None,
+        };
         loop {
-            self.update();
+            self.handle_controls();
+            let tick = self.clock.tick();
+            if end.is_some_and(|end| tick.time >= end as f64) {
+                if !self.config.looping {
+                    return;
+                }
+                self.seek(self.config.start as f64);
+                continue;
+            }
+            self.update(&tick);
             self.draw();
-            std::thread::sleep(std::time::Duration::from_secs_f64(FRAME_TIME));
             self.frame += 1;
+            self.play_audio();
         }
     }
 
-    fn update(&mut self) {
-        self.droplets.update();
-        self.update_visible_notes();
-        for (time, note) in &self.visible_notes {
-            let close_to_end = time - self.time < FRAME_TIME as f32;
-            if close_to_end {
-                let pos = self.pos_for(&(*time, *note));
-                self.droplets.particles_for_note(pos);
+    fn handle_controls(&mut self) {
+        let Some(controls) = &self.controls else {
+            return;
+        };
+        let controls: Vec<Control> = controls.try_iter().collect();
+        for control in controls {
+            if let (Control::Rate(_), Some(_)) = (control, &self.audio) {
+                eprintln!("the audio only plays at rate 1");
+                continue;
             }
+            match control {
+                Control::Seek(time) => self.seek(time),
+                Control::SeekBy(offset) => self.seek(self.clock.time() + offset),
+                control => self.clock.apply(control),
+            }
+            eprintln!(
+                "{:.2}s{}, rate {}, {} frames dropped",
+                self.clock.time(),
+                if self.clock.paused() { " paused" } else { "" },
+                self.clock.rate(),
+                self.clock.dropped_frames()
+            );
         }
     }
 
-    fn draw(&mut self) {
-        // self.canvas.blend_mode = BlendMode::Blend;
-        // self.canvas.pen_color = hex_to_rgb(&PALETTE[PALETTE.len() - 1]);
-        // self.canvas.pen_color[3] = 20;
-        // self.canvas.draw_square(
-        //     Vec2::new(0.0, 0.0),
-        //     Vec2::new((WIDTH) as f32, HEIGHT as f32),
-        // );
-        // self.canvas.blend_mode = BlendMode::Replace;
-        self.canvas.buffer.fill(0);
-        // self.canvas.dim(10);
-        //self.canvas.random();
-        let (low, high) = self.note_lowest_highest;
-        for note in &self.visible_notes {
-            let palette = map(note.1 as f32, low as f32, high as f32, 0.0, 5.0).round() as u8;
-            self.canvas.select_color(palette);
-            let prev_pos = self.pos_for(&(note.0 + FRAME_TIME as f32, note.1));
-            let pos = self.pos_for(note);
-            self.canvas.draw_line(prev_pos, pos);
-        }
-        self.droplets.draw(&mut self.canvas);
-
-        if RECORD {
-            self.ffmpeg
-                .as_mut()
-                .map(|ffmpeg| ffmpeg.write_all(&self.canvas.buffer.as_slice()));
+    fn seek(&mut self, time: f64) {
+        self.clock.seek(time);
+        // Trails from before the jump would smear across it.
+        self.canvas.clear(self.config.background);
+        if let Some(audio) = &mut self.audio {
+            audio.seek(self.clock.time());
         }
-        self.canvas.display();
     }
 
-    fn pos_for(&self, note: &(f32, u8)) -> Vec2 {
-        let (time, note) = note;
-        let time_left = time - self.time;
-        let y = map(time_left, 0f32, VIEW, HEIGHT as f32, 0f32);
-        let slope_offset = map(y, 0.0, HEIGHT as f32, 0.0, SLOPE);
-        let (low, high) = self.note_lowest_highest;
-        let x = map(
-            *note as f32,
-            low as f32,
-            high as f32,
-            SLOPE,
-            WIDTH as f32 - SLOPE,
-        );
-        Vec2::new(x + slope_offset, y)
-    }
-
-    fn update_visible_notes(&mut self) {
-        self.time = self.frame as f32 * FRAME_TIME as f32;
-        let skip = NOTES.partition_point(|(note_time, key)| note_time < &self.time);
-        self.visible_notes = NOTES
-            .iter()
-            .skip(skip)
-            .take_while(|(note_time, key)| note_time < &(self.time + VIEW))
-            .copied()
-            .collect();
+    fn song_end(&self) -> f32 {
+        self.notes.iter().map(Note::end).fold(0.0, f32::max)
     }
 
-    fn canvas() -> Canvas {
-        let mut palette: Vec<_> = PALETTE.iter().map(hex_to_rgb).collect();
-        //palette.extend([[0, 0, 0, 0]].repeat(1));
-        Canvas::new(palette)
-    }
-
-    fn ffmpeg() -> Option<ChildStdin> {
-        let ffmpeg_command = "/usr/bin/ffmpeg";
-        let args = "-y -f rawvideo -vcodec rawvideo -s 640x480 -pix_fmt rgba -r 30 -i - -an -vcodec h264 -pix_fmt yuv420p -crf 15 /home/kirinokirino/Media/video.mp4".split(' ');
-        if RECORD {
-            Some(
-                Command::new(ffmpeg_command)
-                    .args(args)
-                    .stdin(Stdio::piped())
-                    .spawn()
-                    .expect("failed to execute process")
-                    .stdin
-                    .take()
-                    .unwrap(),
-            )
+    /// Queues the audio up to the next frame, blocking while the player's buffer is full.
+    fn play_audio(&mut self) {
+        let Some(audio) = &mut self.audio else {
+            return;
+        };
+        let result = if self.clock.paused() {
+            audio.play_silence(self.clock.frame_time())
         } else {
-            None
+            audio.play_until(self.clock.time() + self.clock.step())
+        };
+        if let Err(err) = result {
+            eprintln!("audio playback stopped: {err}");
+            self.audio = None;
+            self.clock.set_real_time(true);
         }
     }
-}
 
-enum BlendMode {
-    Replace,
-    Blend,
-}
-
-struct Canvas {
-    pub buffer: Vec<u8>,
-    palette: Vec<[u8; 4]>,
-    pub pen_color: [u8; 4],
-    blend_mode: BlendMode,
-}
+    /// Draws every frame up to the end of the song as fast as possible, reporting progress.
+    fn render(&mut self) {
+        let end = (self.song_end() + self.config.tail).min(self.config.end.unwrap_or(f32::MAX));
+        let length = (end - self.config.start).max(0.0) as f64;
+        let frames = (length / self.clock.step()).ceil() as usize;
+        let started = Instant::now();
+        let mut last_report = started;
+        while self.frame < frames && !self.sinks.is_empty() {
+            let tick = self.clock.tick();
+            self.update(&tick);
+            self.draw();
+            self.frame += 1;
+            if last_report.elapsed() >= Duration::from_secs(1) || self.frame == frames {
+                last_report = Instant::now();
+                let speed = self.clock.time() / started.elapsed().as_secs_f64();
+                eprint!(
+                    "\rframe {}/{frames} ({:.0}%), {speed:.1}x real time",
+                    self.frame,
+                    self.frame as f64 / frames as f64 * 100.0
+                );
+            }
+        }
+        eprintln!();
+    }
 
-impl Canvas {
-    pub fn new(palette: Vec<[u8; 4]>) -> Self {
-        let mut buffer = Vec::with_capacity(WIDTH * HEIGHT * 4);
-        unsafe {
-            buffer.set_len(buffer.capacity());
+    fn update(&mut self, tick: &Tick) {
+        let frame_time = self.config.frame_time() as f32;
+        self.time = tick.time as f32;
+        if tick.seeked {
+            self.warm_up();
         }
-        buffer.fill(255);
-        let pen_color = [255, 255, 255, 255];
-        Self {
-            buffer,
-            palette,
-            pen_color,
-            blend_mode: BlendMode::Replace,
+        for _ in 0..tick.steps.min(MAX_CATCH_UP_STEPS) {
+            self.droplets.update(frame_time);
+        }
+        self.update_visible_notes();
+        // Notes landing before the next frame, the ones skipped by dropped frames are lost.
+        let landing = self.triggered_until.max(self.time)..self.time + tick.step as f32;
+        for note in &self.visible_notes {
+            if landing.contains(&note.start) {
+                let pos = self.pos_for(note.start, note.key);
+                self.droplets.particles_for_note(pos, note.velocity);
+            }
         }
+        self.triggered_until = self.triggered_until.max(landing.end);
     }
 
-    pub fn select_color(&mut self, color: u8) {
-        self.pen_color = self.palette[color as usize % self.palette.len()]
+    /// Simulates the droplets of the last moments before `self.time`, so the scene looks
+    /// as if it had played from the start.
+    fn warm_up(&mut self) {
+        let target = self.time;
+        let frame_time = self.config.frame_time() as f32;
+        self.droplets.clear();
+        self.triggered_until = (target - WARM_UP).max(0.0);
+        let frames = (WARM_UP / frame_time).ceil() as usize;
+        for frame in (1..=frames).rev() {
+            let time = target - frame as f32 * frame_time;
+            if time < 0.0 {
+                continue;
+            }
+            self.update(&Tick {
+                time: time as f64,
+                step: frame_time as f64,
+                steps: 1,
+                seeked: false,
+            });
+        }
+        self.time = target;
+        self.triggered_until = target;
+        // Only the frames that are shown draw their landing lines.
+        self.droplets.clear_lines();
     }
 
-    pub fn dim(&mut self, value: i16) {
-        self.buffer.iter_mut().for_each(|v| {
-            let new = (*v as i16 + value).min(255).max(0) as u8;
-            *v = new;
-        });
+    fn draw(&mut self) {
+        let keep = self.config.trail_keep();
+        if keep > 0.0 {
+            self.canvas.fade(self.config.background, keep);
+        } else {
+            self.canvas.clear(self.config.background);
+        }
+        //self.canvas.random();
+        let moments = self.config.motion_blur;
+        if moments == 1 {
+            self.draw_moment(0.0);
+        } else {
+            // Every moment starts from the same faded frame, the average of them is the
+            // frame shown and faded next. The display list keeps the last moment, which
+            // is the present.
+            self.trail.clear();
+            self.trail.extend_from_slice(self.canvas.pixels());
+            self.blur.clear();
+            self.blur.resize(self.trail.len(), 0);
+            for moment in 0..moments {
+                if moment > 0 {
+                    self.canvas.set_pixels(&self.trail);
+                }
+                self.draw_moment((moments - 1 - moment) as f32 / moments as f32);
+                for (sum, value) in self.blur.iter_mut().zip(self.canvas.pixels().iter()) {
+                    *sum += *value as u16;
+                }
+            }
+            for (value, sum) in self.canvas.pixels().iter_mut().zip(&self.blur) {
+                *value = ((*sum as usize + moments / 2) / moments) as u8;
+            }
+        }
+        self.droplets.clear_lines();
+
+        self.write_frame();
     }
 
-    pub fn display(&self) {
-        let file = std::fs::File::options()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open("/tmp/imagesink")
-            .unwrap();
-        let size = 640 * 480 * 4;
-        file.set_len(size.try_into().unwrap()).unwrap();
-        let mut mmap = unsafe { memmap2::MmapMut::map_mut(&file).unwrap() };
-        if let Some(err) = mmap.lock().err() {
-            panic!("{err}");
+    /// Draws the scene as it was `back` frames before the current time.
+    fn draw_moment(&mut self, back: f32) {
+        let now = self.time;
+        self.time -= back * self.config.frame_time() as f32;
+        let (low, high) = self.note_lowest_highest;
+        let colors = self.config.palette.len() as f32;
+        let frame_time = self.config.frame_time() as f32;
+        self.canvas.stroke_width = self.config.note_width();
+        self.canvas.line_cap = LineCap::Round;
+        self.canvas.blend_mode = self.config.note_blend;
+        for note in &self.visible_notes {
+            let palette = map(note.key as f32, low as f32, high as f32, 0.0, colors).round() as u8;
+            self.canvas.select_color(palette);
+            // Notes shorter than a frame still get a frame long trail.
+            let end = note.start + note.duration.max(frame_time);
+            let head = self.pos_for(note.start.max(self.time), note.key);
+            let tail = self.pos_for(end.min(self.time + self.config.view), note.key);
+            self.canvas.draw_line(tail, head);
         }
-        let _ = (&mut mmap[..]).write_all(&self.buffer.as_slice());
+        self.canvas.stroke_width = self.config.stroke();
+        self.canvas.line_cap = LineCap::Butt;
+        self.canvas.blend_mode = self.config.droplet_blend;
+        self.droplets.draw(&mut self.canvas, back);
+        self.time = now;
     }
 
-    fn random(&mut self) {
-        for i in 0..self.buffer.len() / 4 {
-            let mut change = self.palette[fastrand::usize(0..self.palette.len())];
-            change[3] = (change[3] as f32 * 0.05) as u8;
-            self.pen_color = change;
-            self.point_blend(i * 4);
-        }
+    /// Hands the frame to every sink, dropping the ones that failed or are done.
+    fn write_frame(&mut self) {
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        let background = self.canvas.background();
+        let (pixels, commands) = self.canvas.contents();
+        let rgba = if self.effects.is_empty() {
+            pixels
+        } else {
+            self.post.clear();
+            self.post.extend_from_slice(pixels);
+            for effect in &mut self.effects {
+                effect.apply(&mut self.post, width, height);
+            }
+            &self.post
+        };
+        let frame = Frame {
+            rgba,
+            width,
+            height,
+            index: self.frame,
+            commands,
+            background,
+        };
+        self.sinks.retain_mut(|sink| {
+            let result = sink.write(&frame);
+            let done = sink.done();
+            let result = result.and_then(|()| if done { sink.finish() } else { Ok(()) });
+            if let Err(err) = &result {
+                eprintln!("{}: {err}, no more frames go there", sink.name());
+            }
+            result.is_ok() && !done
+        });
     }
 
-    fn draw_curve(&mut self, start: Vec2, control: Vec2, end: Vec2) {
-        let points = start.distance(control) + control.distance(end) + end.distance(start);
-        for i in 1..points as usize {
-            let proportion = i as f32 / points;
-            let path1 = control - start;
-            let point1 = start + path1 * proportion;
-            let path2 = end - control;
-            let point2 = control + path2 * proportion;
-            let path3 = point2 - point1;
-            let point3 = point1 + path3 * proportion;
-            self.draw_point(point3);
-        }
+    fn pos_for(&self, time: f32, note: u8) -> Vec2 {
+        let (width, height) = (self.canvas.width() as f32, self.canvas.height() as f32);
+        let slope = self.config.slope();
+        let time_left = time - self.time;
+        let y = map(time_left, 0f32, self.config.view, height, 0f32);
+        let slope_offset = map(y, 0.0, height, 0.0, slope);
+        let (low, high) = self.note_lowest_highest;
+        let x = map(note as f32, low as f32, high as f32, slope, width - slope);
+        Vec2::new(x + slope_offset, y)
     }
 
-    fn draw_line(&mut self, from: Vec2, to: Vec2) {
-        let delta = to - from;
-        let axis_biggest_distance = (delta.x).abs().max((delta.y).abs()) as usize;
-        let normalized = delta.normalize();
-        for step in 0..axis_biggest_distance {
-            let magnitude = step as f32;
-            let x = from.x + normalized.x * magnitude;
-            let y = from.y + normalized.y * magnitude;
-            self.draw_point(Vec2::new(x, y));
-        }
+    fn update_visible_notes(&mut self) {
+        let frame_time = self.config.frame_time() as f32;
+        // Nothing that started before this can still be sounding.
+        let earliest = self.time - self.longest_note.max(frame_time);
+        let skip = self.notes.partition_point(|note| note.start < earliest);
+        self.visible_notes = self
+            .notes
+            .iter()
+            .skip(skip)
+            .take_while(|note| note.start < self.time + self.config.view)
+            .filter(|note| note.end().max(note.start + frame_time) >= self.time)
+            .copied()
+            .collect();
     }
 
-    fn draw_circle(&mut self, pos: Vec2, radius: f32) {
-        let left_x = (pos.x - radius) as usize;
-        let right_x = (pos.x + radius) as usize;
-        let top_y = (pos.y - radius) as usize;
-        let bottom_y = (pos.y + radius) as usize;
-        for offset_x in left_x..=right_x {
-            for offset_y in top_y..=bottom_y {
-                if ((offset_x as f32 - pos.x as f32).powi(2)
-                    + (offset_y as f32 - pos.y as f32).powi(2))
-                .sqrt()
-                    < radius
-                {
-                    self.draw_point(Vec2::new(offset_x as f32, offset_y as f32));
+    /// The sinks picked in `config`, exits if one can't be opened.
+    fn sinks(notes: &[Note], config: &Config) -> Vec<Box<dyn FrameSink>> {
+        let (width, height) = (config.width, config.height);
+        let open = |kind: &SinkKind| -> std::io::Result<Box<dyn FrameSink>> {
+            Ok(match kind {
+                SinkKind::Display => Box::new(ImageSink::new(&config.image_sink, width, height)?),
+                SinkKind::Ffmpeg => {
+                    let audio = Self::soundtrack_file(notes, &config.soundtrack, config.fps);
+                    Box::new(FfmpegSink::new(config, audio)?)
                 }
-            }
-        }
+                SinkKind::Png => Box::new(PngSink::new(
+                    config.png.dir.as_deref().unwrap_or_default(),
+                    &config.png,
+                )?),
+                SinkKind::Svg => Box::new(SvgSink::new(
+                    config.svg.dir.as_deref().unwrap_or_default(),
+                    &config.svg,
+                )?),
+                SinkKind::Raw => Box::new(RawSink::new(
+                    config.raw.as_deref().unwrap_or_default(),
+                    width,
+                    height,
+                    config.fps,
+                )?),
+                SinkKind::Y4m => Box::new(Y4mSink::new(
+                    config.y4m.as_deref().unwrap_or_default(),
+                    width,
+                    height,
+                    config.fps,
+                )?),
+                SinkKind::Gif => Box::new(GifSink::new(
+                    config.gif.file.as_deref().unwrap_or_default(),
+                    config,
+                )?),
+                SinkKind::Null => Box::new(NullSink),
+            })
+        };
+        config
+            .sinks
+            .iter()
+            .map(|kind| {
+                open(kind).unwrap_or_else(|err| {
+                    eprintln!("{err}");
+                    std::process::exit(1);
+                })
+            })
+            .collect()
     }
 
-    fn draw_square(&mut self, top_left: Vec2, bottom_right: Vec2) {
-        for offset_x in top_left.x as usize..=bottom_right.x as usize {
-            for offset_y in top_left.y as usize..=bottom_right.y as usize {
-                self.draw_point(Vec2::new(offset_x as f32, offset_y as f32));
+    fn effects(config: &Config) -> Vec<Box<dyn Effect>> {
+        let make = |kind: &EffectKind| -> Box<dyn Effect> {
+            match kind {
+                EffectKind::Bloom => Box::new(Bloom::new(
+                    config.glow.threshold,
+                    config.bloom_radius(),
+                    config.glow.strength,
+                )),
+                EffectKind::Vignette => Box::new(Vignette::new(config.vignette)),
+                EffectKind::Grain => Box::new(Grain::new(config.grain)),
+                EffectKind::Aberration => Box::new(Aberration::new(config.aberration())),
+                EffectKind::Scanlines => Box::new(Scanlines::new(
+                    config.scanlines,
+                    (2.0 * config.scale()).round() as usize,
+                )),
+                EffectKind::Grade if config.grade.is_empty() => {
+                    let colors: Vec<[u8; 4]> = std::iter::once([0, 0, 0, 255])
+                        .chain(config.palette.iter().copied())
+                        .collect();
+                    Box::new(Grade::new(&colors, config.grade_mix))
+                }
+                EffectKind::Grade => Box::new(Grade::new(&config.grade, config.grade_mix)),
             }
-        }
+        };
+        config.effects.iter().map(make).collect()
     }
 
-    fn draw_point(&mut self, pos: Vec2) {
-        if pos.x >= 640.0 || pos.x < 0.0 || pos.y >= 480.0 || pos.y < 0.0 {
-            return;
+    /// The user's audio file, or the notes synthesized into a temporary WAV file.
+    fn soundtrack_file(notes: &[Note], soundtrack: &Soundtrack, fps: f64) -> Option<AudioTrack> {
+        if let Some(file) = &soundtrack.file {
+            return Some(AudioTrack::File(file.clone()));
         }
-        let buffer_idx = self.idx(pos.x as usize, pos.y as usize);
-        // if (buffer_idx + 3) > self.buffer.len() {
-        //     // TODO err?
-        //     return;
-        // }
-        match self.blend_mode {
-            BlendMode::Replace => self.point_replace(buffer_idx),
-            BlendMode::Blend => self.point_blend(buffer_idx),
+        let path = std::env::temp_dir().join(format!("sketch-{}.wav", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        match render_wav(notes, &path, soundtrack.sample_rate, fps) {
+            Ok(()) => Some(AudioTrack::Temporary(path)),
+            Err(err) => {
+                eprintln!("{path}: {err}, recording without audio");
+                None
+            }
         }
     }
+}
 
-    fn point_blend(&mut self, buffer_idx: usize) {
-        let [r, g, b, a] = self.pen_color;
-
-        if a == 0 {
-            return;
-        } else if a == 255 {
-            self.point_replace(buffer_idx);
-            return;
-        }
+/// Streams the synthesized song to `aplay`.
+struct Audio {
+    synth: Synth,
+    player: ChildStdin,
+    /// Seconds between a sample being written and being heard.
+    latency: f64,
+}
 
-        let mix = a as f32 / 255.0;
-        let [dst_r, dst_g, dst_b, dst_a] = [
-            self.buffer[buffer_idx] as f32,
-            self.buffer[buffer_idx + 1] as f32,
-            self.buffer[buffer_idx + 2] as f32,
-            self.buffer[buffer_idx + 3] as f32,
+impl Audio {
+    fn new(notes: &[Note], sample_rate: u32) -> Option<Self> {
+        let buffer_time = format!("--buffer-time={}", (AUDIO_BUFFER * 1e6) as u32);
+        let rate = sample_rate.to_string();
+        let args = [
+            "-q",
+            "-t",
+            "raw",
+            "-f",
+            "S16_LE",
+            "-c",
+            "1",
+            "-r",
+            &rate,
+            &buffer_time,
         ];
+        let player = Command::new("aplay")
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| eprintln!("could not start aplay, playing without audio: {err}"))
+            .ok()?
+            .stdin
+            .take()?;
+        // The default 64KiB pipe would hold a third of a second of samples.
+        let pipe_size =
+            unsafe { libc::fcntl(player.as_raw_fd(), libc::F_SETPIPE_SZ, AUDIO_PIPE_SIZE) };
+        let pipe_size = if pipe_size > 0 { pipe_size } else { 65536 };
+        let latency = AUDIO_BUFFER + pipe_size as f64 / 2.0 / sample_rate as f64;
+        Some(Self {
+            synth: Synth::new(notes, sample_rate),
+            player,
+            latency,
+        })
+    }
 
-        self.buffer[buffer_idx] = ((r as f32 * mix) + (dst_r * (1.0 - mix))) as u8;
-        self.buffer[buffer_idx + 1] = ((g as f32 * mix) + (dst_g * (1.0 - mix))) as u8;
-        self.buffer[buffer_idx + 2] = ((b as f32 * mix) + (dst_b * (1.0 - mix))) as u8;
-        self.buffer[buffer_idx + 3] = ((a as f32 * mix) + (dst_a * (1.0 - mix))) as u8;
+    /// Writes the samples up to song `time`, blocking while the player's buffer is full.
+    /// They are written `latency` ahead, so they are heard when that frame is shown.
+    fn play_until(&mut self, time: f64) -> std::io::Result<()> {
+        let samples = self.synth.render_until(time + self.latency);
+        self.write(&samples)
     }
 
-    fn point_replace(&mut self, buffer_idx: usize) {
-        self.buffer[buffer_idx] = self.pen_color[0];
-        self.buffer[buffer_idx + 1] = self.pen_color[1];
-        self.buffer[buffer_idx + 2] = self.pen_color[2];
-        self.buffer[buffer_idx + 3] = self.pen_color[3];
+    fn play_silence(&mut self, seconds: f64) -> std::io::Result<()> {
+        let samples = vec![0.0; (seconds * self.synth.sample_rate() as f64).round() as usize];
+        self.write(&samples)
     }
 
-    fn idx(&self, x: usize, y: usize) -> usize {
-        (x + y * WIDTH) * 4
+    /// Continues from song `time`, the next `play_until` fills the latency from there.
+    fn seek(&mut self, time: f64) {
+        self.synth.seek(time);
     }
-}
 
-fn hex_to_rgb(hex: &&str) -> [u8; 4] {
-    let hex = hex.trim_matches('#');
-    [
-        u8::from_str_radix(&hex[0..2], 16).unwrap(),
-        u8::from_str_radix(&hex[2..4], 16).unwrap(),
-        u8::from_str_radix(&hex[4..6], 16).unwrap(),
-        255,
-    ]
+    fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let pcm: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| wav::to_i16(*sample).to_le_bytes())
+            .collect();
+        self.player.write_all(&pcm)
+    }
 }
 
 pub fn map(value: f32, start1: f32, stop1: f32, start2: f32, stop2: f32) -> f32 {
     (value - start1) / (stop1 - start1) * (stop2 - start2) + start2
 }
 
-pub fn note_find_lowest_highest() -> (u8, u8) {
+pub fn note_find_lowest_highest(notes: &[Note]) -> (u8, u8) {
     let mut lowest = 255u8;
     let mut highest = 0u8;
-    for (_, note) in NOTES {
+    for &Note { key: note, .. } in notes {
         if note > highest {
             highest = note;
         }
@@ -431,5 +744,30 @@ pub fn note_find_lowest_highest() -> (u8, u8) {
             lowest = note;
         }
     }
+    // An empty range would map every note to NaN, center a single key instead.
+    if lowest == highest {
+        return (lowest.saturating_sub(1), highest.saturating_add(1));
+    }
     (lowest, highest)
 }
+
+/// Renders the whole song offline, sample-aligned with a recording at `fps`.
+fn render_wav(notes: &[Note], path: &str, sample_rate: u32, fps: f64) -> std::io::Result<()> {
+    let samples = Synth::new(notes, sample_rate).render_song(fps);
+    let mut file = BufWriter::new(std::fs::File::create(path)?);
+    wav::write(&mut file, &samples, sample_rate)
+}
+
+fn baked_notes() -> Vec<Note> {
+    NOTES
+        .iter()
+        .map(|&(start, key)| Note {
+            start,
+            duration: 0.0,
+            key,
+            velocity: BAKED_VELOCITY,
+            channel: 0,
+            track: 0,
+        })
+        .collect()
+}
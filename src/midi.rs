@@ -0,0 +1,600 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use midly::num::u24;
+use midly::{Format, MidiMessage, Smf, Timing};
+
+/// Tempo assumed by the MIDI spec when a file has no Tempo event (120 bpm).
+const DEFAULT_MICROSECONDS_PER_BEAT: u32 = 500_000;
+
+#[derive(Debug)]
+pub enum SongError {
+    Io(std::io::Error),
+    Parse(midly::Error),
+    ZeroTicksPerBeat,
+}
+
+impl fmt::Display for SongError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SongError::Io(err) => write!(f, "could not read midi file: {err}"),
+            SongError::Parse(err) => write!(f, "could not parse midi file: {err}"),
+            SongError::ZeroTicksPerBeat => write!(f, "midi header declares 0 ticks per beat"),
+        }
+    }
+}
+
+impl std::error::Error for SongError {}
+
+impl From<std::io::Error> for SongError {
+    fn from(err: std::io::Error) -> Self {
+        SongError::Io(err)
+    }
+}
+
+impl From<midly::Error> for SongError {
+    fn from(err: midly::Error) -> Self {
+        SongError::Parse(err)
+    }
+}
+
+/// A channel message placed on the song's absolute tick timeline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    pub tick: u64,
+    /// Index into the file's tracks.
+    pub track: usize,
+    pub channel: u8,
+    pub message: MidiMessage,
+}
+
+/// A sounding note, paired from its NoteOn and NoteOff. Times are in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Note {
+    pub start: f32,
+    pub duration: f32,
+    pub key: u8,
+    pub velocity: u8,
+    pub channel: u8,
+    pub track: usize,
+}
+
+impl Note {
+    pub fn end(&self) -> f32 {
+        self.start + self.duration
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Song {
+    /// Events of all tracks merged in time order.
+    pub events: Vec<Event>,
+    pub time_signature: TimeSignature,
+    pub tempo_map: TempoMap,
+    pub metadata: SongMetadata,
+}
+
+impl Song {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SongError> {
+        let bytes = std::fs::read(path)?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, SongError> {
+        // Smf = Standard Midi File
+        let smf = Smf::parse(bytes)?;
+        // Header { format: SingleTrack, timing: Metrical(u15(384)) }
+        if smf.header.timing == Timing::Metrical(0.into()) {
+            return Err(SongError::ZeroTicksPerBeat);
+        }
+
+        let mut events: Vec<_> = Vec::new();
+        let mut time_signature = None;
+        let mut tempos = Vec::new();
+        let mut metadata = SongMetadata::default();
+        let mut track_start = 0u64;
+        for (track_id, track) in smf.tracks.iter().enumerate() {
+            let mut tick = track_start;
+            for event in track.iter() {
+                tick += u64::from(u32::from(event.delta));
+                match event.kind {
+                    midly::TrackEventKind::Midi { channel, message } => {
+                        events.push(Event {
+                            tick,
+                            track: track_id,
+                            channel: channel.into(),
+                            message,
+                        });
+                    }
+                    midly::TrackEventKind::Meta(variant) => match variant {
+                        midly::MetaMessage::Text(text) => {
+                            metadata.texts.push(TimedText::new(tick, text));
+                        }
+                        midly::MetaMessage::Copyright(text) => {
+                            metadata.copyright = Some(decode_text(text));
+                        }
+                        // The first track's name is the name of the whole sequence.
+                        midly::MetaMessage::TrackName(name) if track_id == 0 => {
+                            metadata.title = Some(decode_text(name));
+                        }
+                        midly::MetaMessage::TrackName(name) => {
+                            metadata.track_names.push((track_id, decode_text(name)));
+                        }
+                        midly::MetaMessage::InstrumentName(name) => {
+                            metadata
+                                .instrument_names
+                                .push((track_id, decode_text(name)));
+                        }
+                        midly::MetaMessage::Lyric(text) => {
+                            metadata.lyrics.push(TimedText::new(tick, text));
+                        }
+                        midly::MetaMessage::Marker(text) => {
+                            metadata.markers.push(TimedText::new(tick, text));
+                        }
+                        midly::MetaMessage::CuePoint(text) => {
+                            metadata.cue_points.push(TimedText::new(tick, text));
+                        }
+                        midly::MetaMessage::Tempo(t) => tempos.push((tick, t)),
+                        midly::MetaMessage::TimeSignature(a, b, c, d) => {
+                            time_signature = Some((a, b, c, d));
+                        }
+                        midly::MetaMessage::KeySignature(sharps, minor) => {
+                            metadata.key_signatures.push(KeySignature {
+                                tick,
+                                seconds: 0.0,
+                                sharps,
+                                minor,
+                            });
+                        }
+                        // Routing and sequencer data don't affect what is drawn.
+                        midly::MetaMessage::TrackNumber(_)
+                        | midly::MetaMessage::ProgramName(_)
+                        | midly::MetaMessage::DeviceName(_)
+                        | midly::MetaMessage::MidiChannel(_)
+                        | midly::MetaMessage::MidiPort(_)
+                        | midly::MetaMessage::EndOfTrack
+                        | midly::MetaMessage::SmpteOffset(_)
+                        | midly::MetaMessage::SequencerSpecific(_)
+                        | midly::MetaMessage::Unknown(_, _) => (),
+                    },
+                    // SysEx and escapes carry nothing we can draw.
+                    midly::TrackEventKind::SysEx(_) | midly::TrackEventKind::Escape(_) => (),
+                }
+            }
+            // Format 2 tracks are independent patterns played one after another.
+            if smf.header.format == Format::Sequential {
+                track_start = tick;
+            }
+        }
+        // Stable, so simultaneous events keep their track order.
+        events.sort_by_key(|event| event.tick);
+        // 4/4 with a metronome click every quarter note, as the spec assumes.
+        let (numerator, denominator, clocks_per_click, _32nd_notes_per_quarter) =
+            time_signature.unwrap_or((4, 2, 24, 8));
+        let time_signature = TimeSignature {
+            numerator,
+            denominator,
+            clocks_per_click,
+            _32nd_notes_per_quarter,
+        };
+        let tempo_map = TempoMap::new(smf.header.timing, tempos);
+        metadata.resolve(&tempo_map);
+        Ok(Self {
+            events,
+            time_signature,
+            tempo_map,
+            metadata,
+        })
+    }
+
+    /// Every note ordered by start. A NoteOn with velocity 0 is a NoteOff, overlapping
+    /// notes on the same key are released first in first out, and notes still held at
+    /// the end of the song last until the last event.
+    pub fn notes(&self) -> Vec<Note> {
+        let mut notes: Vec<Note> = Vec::new();
+        // (track, channel, key) -> start tick and index into `notes` of held notes.
+        let mut held: HashMap<(usize, u8, u8), Vec<(u64, usize)>> = HashMap::new();
+        for event in self.events.iter() {
+            let (key, velocity) = match event.message {
+                MidiMessage::NoteOn { key, vel } => (u8::from(key), u8::from(vel)),
+                MidiMessage::NoteOff { key, vel: _ } => (u8::from(key), 0),
+                _ => continue,
+            };
+            let voice = (event.track, event.channel, key);
+            if velocity > 0 {
+                held.entry(voice)
+                    .or_default()
+                    .push((event.tick, notes.len()));
+                notes.push(Note {
+                    start: self.tempo_map.seconds(event.tick) as f32,
+                    duration: 0.0,
+                    key,
+                    velocity,
+                    channel: event.channel,
+                    track: event.track,
+                });
+            } else if let Some(starts) = held.get_mut(&voice) {
+                if !starts.is_empty() {
+                    let (start, idx) = starts.remove(0);
+                    notes[idx].duration = self.duration(start, event.tick);
+                }
+            }
+        }
+        let last_tick = self.events.last().map_or(0, |event| event.tick);
+        for (start, idx) in held.into_values().flatten() {
+            notes[idx].duration = self.duration(start, last_tick);
+        }
+        notes
+    }
+
+    /// Start time in seconds and key of every note, the same shape as `song::NOTES`.
+    pub fn timeline(&self) -> Vec<(f32, u8)> {
+        self.notes()
+            .iter()
+            .map(|note| (note.start, note.key))
+            .collect()
+    }
+
+    fn duration(&self, start: u64, end: u64) -> f32 {
+        (self.tempo_map.seconds(end) - self.tempo_map.seconds(start)) as f32
+    }
+}
+
+/// Everything a file says about itself besides the notes.
+#[derive(Debug, Clone, Default)]
+pub struct SongMetadata {
+    pub title: Option<String>,
+    pub copyright: Option<String>,
+    pub texts: Vec<TimedText>,
+    pub lyrics: Vec<TimedText>,
+    pub markers: Vec<TimedText>,
+    pub cue_points: Vec<TimedText>,
+    pub key_signatures: Vec<KeySignature>,
+    /// `(track index, name)` for every track but the first.
+    pub track_names: Vec<(usize, String)>,
+    /// `(track index, name)`.
+    pub instrument_names: Vec<(usize, String)>,
+}
+
+impl SongMetadata {
+    /// Sorts the timed entries of all tracks and fills in their seconds.
+    fn resolve(&mut self, tempo_map: &TempoMap) {
+        for texts in [
+            &mut self.texts,
+            &mut self.lyrics,
+            &mut self.markers,
+            &mut self.cue_points,
+        ] {
+            texts.sort_by_key(|text| text.tick);
+            for text in texts.iter_mut() {
+                text.seconds = tempo_map.seconds(text.tick);
+            }
+        }
+        self.key_signatures.sort_by_key(|key| key.tick);
+        for key in self.key_signatures.iter_mut() {
+            key.seconds = tempo_map.seconds(key.tick);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedText {
+    pub tick: u64,
+    pub seconds: f64,
+    pub text: String,
+}
+
+impl TimedText {
+    fn new(tick: u64, text: &[u8]) -> Self {
+        Self {
+            tick,
+            seconds: 0.0,
+            text: decode_text(text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeySignature {
+    pub tick: u64,
+    pub seconds: f64,
+    /// Positive for sharps, negative for flats.
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+/// Meta text has no declared encoding, most files are ASCII or UTF-8.
+fn decode_text(text: &[u8]) -> String {
+    String::from_utf8_lossy(text)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/// Converts absolute ticks to seconds, switching rate at every Tempo event.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    rate: TickRate,
+    /// Sorted by tick, the first one always at tick 0.
+    changes: Vec<TempoChange>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TickRate {
+    Metrical {
+        ticks_per_beat: u16,
+    },
+    /// SMPTE timing counts ticks per second directly, Tempo events don't apply.
+    Timecode {
+        ticks_per_second: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoChange {
+    pub tick: u64,
+    /// Time of `tick`, accumulated over all previous tempo segments.
+    pub seconds: f64,
+    pub microseconds_per_beat: u32,
+}
+
+impl TempoMap {
+    /// `tempos` are `(absolute tick, microseconds per beat)` pairs from any track, in any order.
+    pub fn new(timing: Timing, mut tempos: Vec<(u64, u24)>) -> Self {
+        let rate = match timing {
+            Timing::Metrical(tpb) => TickRate::Metrical {
+                ticks_per_beat: u16::from(tpb).max(1),
+            },
+            Timing::Timecode(fps, subframes) => TickRate::Timecode {
+                ticks_per_second: fps.as_f32() as f64 * subframes.max(1) as f64,
+            },
+        };
+        // Stable, so of several tempos on the same tick the last one wins.
+        tempos.sort_by_key(|(tick, _)| *tick);
+        let mut changes = vec![TempoChange {
+            tick: 0,
+            seconds: 0.0,
+            microseconds_per_beat: DEFAULT_MICROSECONDS_PER_BEAT,
+        }];
+        for (tick, tempo) in tempos {
+            let seconds = Self::seconds_from(rate, changes.last().unwrap(), tick);
+            let change = TempoChange {
+                tick,
+                seconds,
+                microseconds_per_beat: u32::from(tempo),
+            };
+            match changes.last_mut() {
+                Some(last) if last.tick == tick => *last = change,
+                _ => changes.push(change),
+            }
+        }
+        Self { rate, changes }
+    }
+
+    pub fn seconds(&self, tick: u64) -> f64 {
+        let segment = self.changes.partition_point(|change| change.tick <= tick) - 1;
+        Self::seconds_from(self.rate, &self.changes[segment], tick)
+    }
+
+    pub fn changes(&self) -> &[TempoChange] {
+        &self.changes
+    }
+
+    fn seconds_from(rate: TickRate, change: &TempoChange, tick: u64) -> f64 {
+        let ticks = (tick - change.tick) as f64;
+        let elapsed = match rate {
+            TickRate::Metrical { ticks_per_beat } => {
+                ticks * change.microseconds_per_beat as f64
+                    / ticks_per_beat as f64
+                    / (1000.0 * 1000.0)
+            }
+            TickRate::Timecode { ticks_per_second } => ticks / ticks_per_second,
+        };
+        change.seconds + elapsed
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    pub denominator: u8,
+    pub clocks_per_click: u8,
+    pub _32nd_notes_per_quarter: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPO_120: u32 = 500_000;
+    const TEMPO_240: u32 = 250_000;
+
+    fn note_on(key: u8, vel: u8) -> Vec<u8> {
+        vec![0x90, key, vel]
+    }
+
+    fn note_off(key: u8) -> Vec<u8> {
+        vec![0x80, key, 64]
+    }
+
+    fn tempo(microseconds_per_beat: u32) -> Vec<u8> {
+        let [_, a, b, c] = microseconds_per_beat.to_be_bytes();
+        vec![0xff, 0x51, 3, a, b, c]
+    }
+
+    /// A track chunk of `(delta ticks, event)`, ended for it.
+    fn track(events: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (delta, event) in events {
+            // Variable length, 7 bits at a time with the high bit set on all but the last.
+            let mut bytes = vec![(delta & 0x7f) as u8];
+            let mut rest = delta >> 7;
+            while rest > 0 {
+                bytes.push((rest & 0x7f) as u8 | 0x80);
+                rest >>= 7;
+            }
+            data.extend(bytes.iter().rev());
+            data.extend(event);
+        }
+        data.extend([0, 0xff, 0x2f, 0]);
+        let mut chunk = b"MTrk".to_vec();
+        chunk.extend((data.len() as u32).to_be_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    /// A file of `format` with the raw `division` word of the header.
+    fn smf(format: u16, division: [u8; 2], tracks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend(6u32.to_be_bytes());
+        bytes.extend(format.to_be_bytes());
+        bytes.extend((tracks.len() as u16).to_be_bytes());
+        bytes.extend(division);
+        for track in tracks {
+            bytes.extend(track);
+        }
+        bytes
+    }
+
+    fn metrical(ticks_per_beat: u16) -> [u8; 2] {
+        ticks_per_beat.to_be_bytes()
+    }
+
+    /// `(start, duration, key)` of every note.
+    fn notes(bytes: &[u8]) -> Vec<(f32, f32, u8)> {
+        let song = Song::parse(bytes).unwrap();
+        song.notes()
+            .iter()
+            .map(|note| (note.start, note.duration, note.key))
+            .collect()
+    }
+
+    #[test]
+    fn tempo_change_in_another_track() {
+        let tempos = track(&[(0, tempo(TEMPO_120)), (480, tempo(TEMPO_240))]);
+        let melody = track(&[
+            (0, note_on(60, 100)),
+            (240, note_off(60)),
+            (0, note_on(62, 100)),
+            (240, note_off(62)),
+            (480, note_on(64, 100)),
+            (480, note_off(64)),
+        ]);
+        let bytes = smf(1, metrical(480), &[tempos, melody]);
+        assert_eq!(
+            notes(&bytes),
+            [(0.0, 0.25, 60), (0.25, 0.25, 62), (0.75, 0.25, 64)]
+        );
+    }
+
+    #[test]
+    fn tempo_map_segments() {
+        let map = TempoMap::new(
+            Timing::Metrical(480.into()),
+            vec![
+                (960, TEMPO_120.into()),
+                (480, TEMPO_240.into()),
+                // The later of two on the same tick wins.
+                (480, 1_000_000.into()),
+            ],
+        );
+        assert_eq!(map.changes().len(), 3);
+        // 120 bpm by default, then 60 and 120 again.
+        assert_eq!(map.seconds(240), 0.25);
+        assert_eq!(map.seconds(480), 0.5);
+        assert_eq!(map.seconds(720), 1.0);
+        assert_eq!(map.seconds(960), 1.5);
+        assert_eq!(map.seconds(1200), 1.75);
+    }
+
+    #[test]
+    fn smpte_timing_ignores_tempo() {
+        // 25 frames of 40 ticks a second.
+        let division = [(-25i8) as u8, 40];
+        let bytes = smf(
+            0,
+            division,
+            &[track(&[
+                (0, tempo(TEMPO_240)),
+                (500, note_on(60, 100)),
+                (250, note_off(60)),
+            ])],
+        );
+        assert_eq!(notes(&bytes), [(0.5, 0.25, 60)]);
+    }
+
+    #[test]
+    fn format_1_tracks_merge_by_time() {
+        let first = track(&[
+            (0, note_on(60, 100)),
+            (120, note_off(60)),
+            (360, note_on(64, 100)),
+            (120, note_off(64)),
+        ]);
+        let second = track(&[(240, note_on(62, 100)), (120, note_off(62))]);
+        let bytes = smf(1, metrical(480), &[first, second]);
+        let song = Song::parse(&bytes).unwrap();
+        let notes: Vec<(f32, u8, usize)> = song
+            .notes()
+            .iter()
+            .map(|note| (note.start, note.key, note.track))
+            .collect();
+        assert_eq!(notes, [(0.0, 60, 0), (0.25, 62, 1), (0.5, 64, 0)]);
+    }
+
+    #[test]
+    fn format_2_tracks_play_one_after_another() {
+        let first = track(&[(0, note_on(60, 100)), (480, note_off(60))]);
+        let second = track(&[(0, note_on(62, 100)), (240, note_off(62))]);
+        let bytes = smf(2, metrical(480), &[first, second]);
+        assert_eq!(notes(&bytes), [(0.0, 0.5, 60), (0.5, 0.25, 62)]);
+    }
+
+    #[test]
+    fn velocity_0_ends_a_note() {
+        let bytes = smf(
+            0,
+            metrical(480),
+            &[track(&[(0, note_on(60, 100)), (240, note_on(60, 0))])],
+        );
+        assert_eq!(notes(&bytes), [(0.0, 0.25, 60)]);
+    }
+
+    #[test]
+    fn overlapping_notes_on_a_key_end_in_order() {
+        let bytes = smf(
+            0,
+            metrical(480),
+            &[track(&[
+                (0, note_on(60, 100)),
+                (240, note_on(60, 80)),
+                (240, note_off(60)),
+                (480, note_off(60)),
+            ])],
+        );
+        assert_eq!(notes(&bytes), [(0.0, 0.5, 60), (0.25, 0.75, 60)]);
+    }
+
+    #[test]
+    fn held_notes_last_until_the_last_event() {
+        let bytes = smf(
+            0,
+            metrical(480),
+            &[track(&[
+                (0, note_on(60, 100)),
+                (240, note_on(64, 100)),
+                (720, note_off(64)),
+            ])],
+        );
+        assert_eq!(notes(&bytes), [(0.0, 1.0, 60), (0.25, 0.75, 64)]);
+    }
+
+    #[test]
+    fn zero_ticks_per_beat_is_an_error() {
+        let bytes = smf(0, metrical(0), &[track(&[])]);
+        assert!(matches!(
+            Song::parse(&bytes),
+            Err(SongError::ZeroTicksPerBeat)
+        ));
+    }
+}
@@ -0,0 +1,347 @@
+use std::io::{self, Write};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Base lengths of the deflate length codes 257..=285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances of the deflate distance codes 0..=29.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const MAX_DISTANCE: usize = 32768;
+
+/// Writes an 8-bit RGBA image. `rgba` holds `width * height` pixels, rows top to bottom.
+pub fn write(out: &mut impl Write, width: usize, height: usize, rgba: &[u8]) -> io::Result<()> {
+    assert_eq!(rgba.len(), width * height * 4, "image size doesn't match");
+    let too_big = || io::Error::new(io::ErrorKind::InvalidInput, "too big for a png file");
+    let mut header = Vec::with_capacity(13);
+    header.extend(u32::try_from(width).map_err(|_| too_big())?.to_be_bytes());
+    header.extend(u32::try_from(height).map_err(|_| too_big())?.to_be_bytes());
+    // 8 bits per channel, color type 6 = RGBA, deflate, adaptive filters, no interlace.
+    header.extend([8, 6, 0, 0, 0]);
+
+    // Every row starts with its filter type, 0 = none.
+    let stride = width * 4 + 1;
+    let mut raw = Vec::with_capacity(stride * height);
+    for row in rgba.chunks_exact(width * 4) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    out.write_all(&SIGNATURE)?;
+    chunk(out, b"IHDR", &header)?;
+    chunk(out, b"IDAT", &zlib(&raw, stride))?;
+    chunk(out, b"IEND", &[])?;
+    out.flush()
+}
+
+fn chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "png chunk too long"))?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(crc32(!0, kind), data);
+    out.write_all(&(!crc).to_be_bytes())
+}
+
+/// Compresses `data` into a zlib stream of a single fixed Huffman deflate block. Matches
+/// are only searched one byte, one pixel and one row (`stride`) back, which is where
+/// repetition is in a frame of flat shapes on an empty background.
+fn zlib(data: &[u8], stride: usize) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    // Final block, fixed Huffman codes.
+    bits.write(1, 1);
+    bits.write(1, 2);
+    let distances = [1, 4, stride];
+    let mut pos = 0;
+    while pos < data.len() {
+        let (length, distance) = distances
+            .iter()
+            .filter(|distance| **distance <= pos && **distance <= MAX_DISTANCE)
+            .map(|distance| (match_length(data, pos, *distance), *distance))
+            .max_by_key(|(length, _)| *length)
+            .unwrap_or((0, 0));
+        if length >= MIN_MATCH {
+            bits.length(length);
+            bits.distance(distance);
+            pos += length;
+        } else {
+            bits.literal(data[pos] as u16);
+            pos += 1;
+        }
+    }
+    bits.literal(256);
+
+    let mut out = vec![0x78, 0x01];
+    out.extend(bits.finish());
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn match_length(data: &[u8], pos: usize, distance: usize) -> usize {
+    let max = MAX_MATCH.min(data.len() - pos);
+    (0..max)
+        .take_while(|i| data[pos + i] == data[pos + i - distance])
+        .count()
+}
+
+/// Packs bits least significant first, as deflate wants them.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u32,
+    filled: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.current |= value << self.filled;
+        self.filled += count;
+        while self.filled >= 8 {
+            self.bytes.push(self.current as u8);
+            self.current >>= 8;
+            self.filled -= 8;
+        }
+    }
+
+    /// Huffman codes go in most significant bit first.
+    fn code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    /// Literal bytes and the end of block marker 256, with the fixed codes.
+    fn literal(&mut self, value: u16) {
+        let value = value as u32;
+        match value {
+            0..=143 => self.code(0b0011_0000 + value, 8),
+            144..=255 => self.code(0b1_1001_0000 + value - 144, 9),
+            256..=279 => self.code(value - 256, 7),
+            _ => self.code(0b1100_0000 + value - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let idx = LENGTH_BASE.partition_point(|base| *base as usize <= length) - 1;
+        self.literal(257 + idx as u16);
+        let extra = length as u32 - LENGTH_BASE[idx] as u32;
+        self.write(extra, LENGTH_EXTRA[idx] as u32);
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let idx = DISTANCE_BASE.partition_point(|base| *base as usize <= distance) - 1;
+        self.code(idx as u32, 5);
+        let extra = distance as u32 - DISTANCE_BASE[idx] as u32;
+        self.write(extra, DISTANCE_EXTRA[idx] as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.filled > 0 {
+            self.bytes.push(self.current as u8);
+        }
+        self.bytes
+    }
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that can be summed before `b` could overflow.
+    for block in data.chunks(5552) {
+        for byte in block {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits least significant first, Huffman codes most significant first.
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: usize) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let bit = self.bytes[self.pos / 8] >> (self.pos % 8) & 1;
+                value |= (bit as u32) << i;
+                self.pos += 1;
+            }
+            value
+        }
+
+        fn code(&mut self, count: usize) -> u32 {
+            (0..count).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        /// A literal/length symbol of the fixed codes.
+        fn symbol(&mut self) -> u32 {
+            let code = self.code(7);
+            if code <= 0b001_0111 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0b0011_0000..=0b1011_1111 => code - 0b0011_0000,
+                0b1100_0000..=0b1100_0111 => 280 + code - 0b1100_0000,
+                _ => 144 + (code << 1 | self.bits(1)) - 0b1_1001_0000,
+            }
+        }
+    }
+
+    /// The data of a zlib stream of fixed Huffman blocks, and every match distance in it.
+    fn inflate(stream: &[u8]) -> (Vec<u8>, Vec<usize>) {
+        assert_eq!((stream[0] as u16 * 256 + stream[1] as u16) % 31, 0);
+        let mut bits = BitReader {
+            bytes: &stream[2..stream.len() - 4],
+            pos: 0,
+        };
+        let (mut data, mut distances) = (Vec::new(), Vec::new());
+        loop {
+            let last = bits.bits(1);
+            assert_eq!(bits.bits(2), 1, "not a fixed Huffman block");
+            loop {
+                let symbol = bits.symbol() as usize;
+                match symbol {
+                    0..=255 => data.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let idx = symbol - 257;
+                        let length = LENGTH_BASE[idx] as usize
+                            + bits.bits(LENGTH_EXTRA[idx] as usize) as usize;
+                        let idx = bits.code(5) as usize;
+                        let distance = DISTANCE_BASE[idx] as usize
+                            + bits.bits(DISTANCE_EXTRA[idx] as usize) as usize;
+                        distances.push(distance);
+                        for _ in 0..length {
+                            data.push(data[data.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last == 1 {
+                break;
+            }
+        }
+        let checksum = u32::from_be_bytes(stream[stream.len() - 4..].try_into().unwrap());
+        assert_eq!(checksum, adler32(&data));
+        (data, distances)
+    }
+
+    /// Writes `rgba` as a png and reads it back, checking every chunk on the way. Returns
+    /// the match distances the image data was compressed with.
+    fn round_trip(width: usize, height: usize, rgba: &[u8]) -> Vec<usize> {
+        let mut file = Vec::new();
+        write(&mut file, width, height, rgba).unwrap();
+        assert_eq!(file[..8], SIGNATURE);
+        let mut rest = &file[8..];
+        let (mut header, mut idat, mut ended) = (Vec::new(), Vec::new(), false);
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, !crc32(crc32(!0, kind), data));
+            match kind {
+                b"IHDR" => header = data.to_vec(),
+                b"IDAT" => idat.extend(data),
+                b"IEND" => ended = true,
+                _ => panic!("unexpected chunk"),
+            }
+            rest = &rest[12 + len..];
+        }
+        assert!(ended);
+        assert_eq!(header[..4], (width as u32).to_be_bytes());
+        assert_eq!(header[4..8], (height as u32).to_be_bytes());
+        let (raw, distances) = inflate(&idat);
+        let stride = width * 4 + 1;
+        assert_eq!(raw.len(), stride * height);
+        let mut pixels: Vec<u8> = Vec::new();
+        for row in raw.chunks_exact(stride) {
+            assert_eq!(row[0], 0, "filtered row");
+            pixels.extend(&row[1..]);
+        }
+        assert!(pixels == rgba, "pixels differ after decoding");
+        distances
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(!crc32(!0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[]), 1);
+        // Long enough for the sums to be reduced along the way.
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+    }
+
+    #[test]
+    fn runs_of_a_byte_match_one_back() {
+        let (width, height) = (7, 5);
+        let distances = round_trip(width, height, &vec![9; width * height * 4]);
+        assert!(distances.contains(&1));
+    }
+
+    #[test]
+    fn repeated_pixels_match_one_pixel_back() {
+        let (width, height) = (7, 5);
+        let distances = round_trip(width, height, &[10, 20, 30, 255].repeat(width * height));
+        assert!(distances.contains(&4));
+    }
+
+    #[test]
+    fn repeated_rows_match_one_row_back() {
+        let (width, height) = (7, 5);
+        let mut rng = fastrand::Rng::with_seed(1);
+        let row: Vec<u8> = (0..width * 4).map(|_| rng.u8(..)).collect();
+        let distances = round_trip(width, height, &row.repeat(height));
+        assert!(distances.contains(&(width * 4 + 1)));
+    }
+
+    #[test]
+    fn noise_and_long_runs() {
+        let (width, height) = (61, 13);
+        let mut rng = fastrand::Rng::with_seed(2);
+        let mut rgba: Vec<u8> = (0..width * height * 4).map(|_| rng.u8(..)).collect();
+        // Longer than the longest match.
+        rgba[1000..1700].fill(0);
+        round_trip(width, height, &rgba);
+    }
+
+    #[test]
+    fn rows_too_far_apart_to_match() {
+        let (width, height) = (8200, 2);
+        let rgba: Vec<u8> = (0..width * 4).map(|i| (i % 251) as u8).collect();
+        let distances = round_trip(width, height, &rgba.repeat(height));
+        assert!(distances.iter().all(|distance| *distance <= MAX_DISTANCE));
+    }
+}
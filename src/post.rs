@@ -0,0 +1,341 @@
+use std::thread;
+
+/// A pass over a finished frame, before it goes to the sinks. Frames are RGBA8 with the
+/// colors premultiplied by alpha, like the canvas draws them.
+pub trait Effect {
+    fn apply(&mut self, rgba: &mut [u8], width: usize, height: usize);
+}
+
+/// Makes bright parts glow: whatever is brighter than `threshold` gets blurred and added
+/// back on top.
+pub struct Bloom {
+    /// Luma in 0..1 below which nothing glows.
+    threshold: f32,
+    /// How far the glow reaches in pixels, about three standard deviations of the blur.
+    radius: f32,
+    /// How much of the blurred light is added, 1 for all of it.
+    strength: f32,
+    /// RGB of the bright parts, also the result of the second blur pass.
+    bright: Vec<f32>,
+    /// RGB after the first blur pass.
+    blurred: Vec<f32>,
+}
+
+impl Bloom {
+    pub fn new(threshold: f32, radius: f32, strength: f32) -> Self {
+        Self {
+            threshold,
+            radius,
+            strength,
+            bright: Vec::new(),
+            blurred: Vec::new(),
+        }
+    }
+}
+
+impl Effect for Bloom {
+    fn apply(&mut self, rgba: &mut [u8], width: usize, height: usize) {
+        let kernel = gaussian(self.radius);
+        let reach = kernel.len() / 2;
+        let row_len = width * 3;
+
+        self.bright.clear();
+        for pixel in rgba.chunks_exact(4) {
+            let rgb = [0, 1, 2].map(|channel| pixel[channel] as f32 / 255.0);
+            let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+            // Scaled down rather than cut off, so the glow doesn't start with a step.
+            let keep = if luma > self.threshold {
+                (luma - self.threshold) / luma
+            } else {
+                0.0
+            };
+            self.bright.extend(rgb.map(|channel| channel * keep));
+        }
+        self.blurred.resize(self.bright.len(), 0.0);
+
+        // The blur is separable, rows first and then columns. Light from outside the
+        // frame counts as black.
+        let bright = &self.bright;
+        for_rows(&mut self.blurred, row_len, |y, row| {
+            let line = &bright[y * row_len..(y + 1) * row_len];
+            for (x, out) in row.chunks_exact_mut(3).enumerate() {
+                let mut sum = [0.0; 3];
+                let first = x.saturating_sub(reach);
+                let last = (x + reach).min(width - 1);
+                for sx in first..=last {
+                    let weight = kernel[sx + reach - x];
+                    for channel in 0..3 {
+                        sum[channel] += line[sx * 3 + channel] * weight;
+                    }
+                }
+                out.copy_from_slice(&sum);
+            }
+        });
+        let blurred = &self.blurred;
+        for_rows(&mut self.bright, row_len, |y, row| {
+            row.fill(0.0);
+            let first = y.saturating_sub(reach);
+            let last = (y + reach).min(height - 1);
+            for sy in first..=last {
+                let weight = kernel[sy + reach - y];
+                let line = &blurred[sy * row_len..(sy + 1) * row_len];
+                for (out, value) in row.iter_mut().zip(line) {
+                    *out += value * weight;
+                }
+            }
+        });
+
+        let (glow, strength) = (&self.bright, self.strength);
+        for_rows(rgba, width * 4, |y, row| {
+            let glow = &glow[y * row_len..(y + 1) * row_len];
+            for (pixel, light) in row.chunks_exact_mut(4).zip(glow.chunks_exact(3)) {
+                for channel in 0..3 {
+                    let value = pixel[channel] as f32 + light[channel] * strength * 255.0;
+                    pixel[channel] = value.round().min(255.0) as u8;
+                }
+                // Glow over transparent parts makes them as opaque as it is bright.
+                pixel[3] = pixel[3].max(pixel[0]).max(pixel[1]).max(pixel[2]);
+            }
+        });
+    }
+}
+
+/// Weights of a Gaussian blur reaching `radius` pixels to each side, summing up to 1.
+fn gaussian(radius: f32) -> Vec<f32> {
+    let reach = radius.ceil().max(0.0) as isize;
+    let sigma = (radius / 3.0).max(0.5);
+    let mut kernel: Vec<f32> = (-reach..=reach)
+        .map(|offset| (-(offset * offset) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|weight| *weight /= sum);
+    kernel
+}
+
+/// Calls `f` with the index and contents of every `row_len` long row of `data`, the rows
+/// split into one band per core.
+fn for_rows<T: Send>(data: &mut [T], row_len: usize, f: impl Fn(usize, &mut [T]) + Sync) {
+    let rows = data.len() / row_len;
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    let band = rows.div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        for (idx, chunk) in data.chunks_mut(band * row_len).enumerate() {
+            let f = &f;
+            scope.spawn(move || {
+                for (offset, row) in chunk.chunks_exact_mut(row_len).enumerate() {
+                    f(idx * band + offset, row);
+                }
+            });
+        }
+    });
+}
+
+/// Darkens towards the corners.
+pub struct Vignette {
+    /// How much darker the corners get, 0..=1.
+    strength: f32,
+}
+
+impl Vignette {
+    pub fn new(strength: f32) -> Self {
+        Self {
+            strength: strength.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Effect for Vignette {
+    fn apply(&mut self, rgba: &mut [u8], width: usize, height: usize) {
+        let center = (width as f32 / 2.0, height as f32 / 2.0);
+        for_rows(rgba, width * 4, |y, row| {
+            let dy = (y as f32 + 0.5 - center.1) / center.1;
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let dx = (x as f32 + 0.5 - center.0) / center.0;
+                // Squared distance from the center, 1 in the corners.
+                let distance = (dx * dx + dy * dy) / 2.0;
+                let shade = 1.0 - self.strength * distance;
+                for channel in &mut pixel[..3] {
+                    *channel = (*channel as f32 * shade).round() as u8;
+                }
+            }
+        });
+    }
+}
+
+/// Monochrome noise, different in every frame.
+pub struct Grain {
+    /// Largest change of a channel, 0..=1.
+    amount: f32,
+}
+
+impl Grain {
+    pub fn new(amount: f32) -> Self {
+        Self {
+            amount: amount.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Effect for Grain {
+    fn apply(&mut self, rgba: &mut [u8], width: usize, _height: usize) {
+        let amount = self.amount * 255.0;
+        for_rows(rgba, width * 4, |_, row| {
+            let mut rng = fastrand::Rng::new();
+            for pixel in row.chunks_exact_mut(4) {
+                let noise = (rng.f32() * 2.0 - 1.0) * amount;
+                for channel in &mut pixel[..3] {
+                    *channel = (*channel as f32 + noise).round().clamp(0.0, 255.0) as u8;
+                }
+                // Grain over transparent parts shows like it would over black.
+                pixel[3] = pixel[3].max(pixel[0]).max(pixel[1]).max(pixel[2]);
+            }
+        });
+    }
+}
+
+/// Red and blue drift apart towards the edges like through a cheap lens, red outwards
+/// and blue inwards.
+pub struct Aberration {
+    /// How far in pixels they drift in the corners.
+    offset: f32,
+    /// The frame before the drift.
+    source: Vec<u8>,
+}
+
+impl Aberration {
+    pub fn new(offset: f32) -> Self {
+        Self {
+            offset,
+            source: Vec::new(),
+        }
+    }
+}
+
+impl Effect for Aberration {
+    fn apply(&mut self, rgba: &mut [u8], width: usize, height: usize) {
+        self.source.clear();
+        self.source.extend_from_slice(rgba);
+        let source = &self.source;
+        let center = (width as f32 / 2.0, height as f32 / 2.0);
+        // Drift relative to the distance from the center.
+        let drift = self.offset / center.0.hypot(center.1);
+        for_rows(rgba, width * 4, |y, row| {
+            let dy = y as f32 + 0.5 - center.1;
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let dx = x as f32 + 0.5 - center.0;
+                let at = |scale: f32| {
+                    let (sx, sy) = (center.0 + dx * scale, center.1 + dy * scale);
+                    sample(source, width, height, sx - 0.5, sy - 0.5)
+                };
+                let (red, blue) = (at(1.0 - drift), at(1.0 + drift));
+                pixel[0] = red[0].round() as u8;
+                pixel[2] = blue[2].round() as u8;
+                let alpha = red[3].max(blue[3]).round() as u8;
+                pixel[3] = pixel[3].max(alpha);
+            }
+        });
+    }
+}
+
+/// Bilinear sample of the pixel at `x`, `y`, transparent outside of the frame.
+fn sample(rgba: &[u8], width: usize, height: usize, x: f32, y: f32) -> [f32; 4] {
+    let (left, top) = (x.floor(), y.floor());
+    let (fx, fy) = (x - left, y - top);
+    let mut out = [0.0; 4];
+    for (dy, wy) in [(0, 1.0 - fy), (1, fy)] {
+        for (dx, wx) in [(0, 1.0 - fx), (1, fx)] {
+            let (px, py) = (left as isize + dx, top as isize + dy);
+            if px < 0 || py < 0 || px >= width as isize || py >= height as isize {
+                continue;
+            }
+            let idx = (py as usize * width + px as usize) * 4;
+            for channel in 0..4 {
+                out[channel] += rgba[idx + channel] as f32 * wx * wy;
+            }
+        }
+    }
+    out
+}
+
+/// Darkens every other line like an old CRT.
+pub struct Scanlines {
+    /// How much darker the lines in between get, 0..=1.
+    darkness: f32,
+    /// Rows from one line to the next, half of them dark.
+    period: usize,
+}
+
+impl Scanlines {
+    pub fn new(darkness: f32, period: usize) -> Self {
+        Self {
+            darkness: darkness.clamp(0.0, 1.0),
+            period: period.max(2),
+        }
+    }
+}
+
+impl Effect for Scanlines {
+    fn apply(&mut self, rgba: &mut [u8], width: usize, _height: usize) {
+        let shade = 1.0 - self.darkness;
+        for_rows(rgba, width * 4, |y, row| {
+            if y % self.period < self.period / 2 {
+                return;
+            }
+            for pixel in row.chunks_exact_mut(4) {
+                for channel in &mut pixel[..3] {
+                    *channel = (*channel as f32 * shade).round() as u8;
+                }
+            }
+        });
+    }
+}
+
+/// Recolors by brightness along a gradient of colors, from dark to bright.
+pub struct Grade {
+    /// The graded color for every luma.
+    lut: Vec<[f32; 3]>,
+    /// How much of the graded color replaces the drawn one, 0..=1.
+    mix: f32,
+}
+
+impl Grade {
+    pub fn new(colors: &[[u8; 4]], mix: f32) -> Self {
+        let last = colors.len().saturating_sub(1);
+        let lut = (0..256)
+            .map(|luma| {
+                let pos = luma as f32 / 255.0 * last as f32;
+                let (idx, t) = (pos.floor() as usize, pos.fract());
+                let (from, to) = (colors[idx], colors[(idx + 1).min(last)]);
+                [0, 1, 2].map(|channel| {
+                    from[channel] as f32 + (to[channel] as f32 - from[channel] as f32) * t
+                })
+            })
+            .collect();
+        Self {
+            lut,
+            mix: mix.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Effect for Grade {
+    fn apply(&mut self, rgba: &mut [u8], width: usize, _height: usize) {
+        let (lut, mix) = (&self.lut, self.mix);
+        for_rows(rgba, width * 4, |_, row| {
+            for pixel in row.chunks_exact_mut(4) {
+                let alpha = pixel[3] as f32 / 255.0;
+                if alpha == 0.0 {
+                    continue;
+                }
+                // The gradient is for straight colors, the frame is premultiplied.
+                let rgb = [0, 1, 2].map(|channel| pixel[channel] as f32 / alpha);
+                let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+                let graded = lut[(luma.round() as usize).min(255)];
+                for channel in 0..3 {
+                    let value = rgb[channel] + (graded[channel] - rgb[channel]) * mix;
+                    pixel[channel] = (value * alpha).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,566 @@
+use glam::{Vec2, Vec2Swizzles};
+
+use std::ops::{Range, RangeInclusive};
+
+use crate::canvas::{BlendMode, LineCap, LineJoin};
+
+/// Longest miter join, in stroke widths.
+const MITER_LIMIT: f32 = 4.0;
+
+/// How a command is drawn, as the canvas was set up when it was recorded.
+#[derive(Debug, Clone, Copy)]
+pub struct Pen {
+    pub color: [u8; 4],
+    pub blend_mode: BlendMode,
+    pub antialias: bool,
+    pub stroke_width: f32,
+    pub line_cap: LineCap,
+    pub line_join: LineJoin,
+}
+
+/// What a draw call draws, in pixels of the canvas.
+#[derive(Debug, Clone)]
+pub enum Shape {
+    Point(Vec2),
+    Line(Vec2, Vec2),
+    /// A quadratic Bézier curve from the first point through the second to the third.
+    Curve(Vec2, Vec2, Vec2),
+    /// A line through the points, back to the first one if it's `closed`.
+    Stroke {
+        points: Vec<Vec2>,
+        closed: bool,
+    },
+    Circle {
+        center: Vec2,
+        radius: f32,
+        filled: bool,
+    },
+    /// A round dot fading out from the middle to `radius`.
+    Dot {
+        center: Vec2,
+        radius: f32,
+    },
+    RoundedRect {
+        top_left: Vec2,
+        bottom_right: Vec2,
+        radius: f32,
+        filled: bool,
+    },
+    /// Filled by the even-odd rule.
+    Polygon(Vec<Vec2>),
+}
+
+/// A draw call recorded by the canvas, to rasterize later.
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub shape: Shape,
+    pub pen: Pen,
+}
+
+impl Command {
+    /// Top and bottom of what the command may draw, with room to spare.
+    fn rows(&self) -> (f32, f32) {
+        let span = |points: &[Vec2]| {
+            points
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(top, bottom), point| {
+                    (top.min(point.y), bottom.max(point.y))
+                })
+        };
+        let (top, bottom) = match &self.shape {
+            Shape::Point(pos) => (pos.y, pos.y),
+            Shape::Line(from, to) => span(&[*from, *to]),
+            // A curve stays between its points.
+            Shape::Curve(start, control, end) => span(&[*start, *control, *end]),
+            Shape::Stroke { points, .. } | Shape::Polygon(points) => span(points),
+            Shape::Circle { center, radius, .. } | Shape::Dot { center, radius } => {
+                (center.y - radius, center.y + radius)
+            }
+            Shape::RoundedRect {
+                top_left,
+                bottom_right,
+                ..
+            } => span(&[*top_left, *bottom_right]),
+        };
+        // Miters reach farthest past the points, and every shape a pixel around itself.
+        let margin = self.pen.stroke_width * MITER_LIMIT + 2.0;
+        (top - margin, bottom + margin)
+    }
+}
+
+/// Rows of the canvas that commands are rasterized into, so that several of them can be
+/// drawn at once. Every pixel comes out exactly as if the whole canvas was drawn in one.
+pub struct Band<'a> {
+    /// The pixels of `rows`.
+    pixels: &'a mut [u8],
+    width: usize,
+    height: usize,
+    rows: Range<usize>,
+    pen: Pen,
+}
+
+impl<'a> Band<'a> {
+    /// `pixels` are whole rows of a `width` by `height` canvas, starting at row `top`.
+    pub fn new(pixels: &'a mut [u8], width: usize, height: usize, top: usize) -> Self {
+        let rows = top..top + pixels.len() / (width * 4);
+        Self {
+            pixels,
+            width,
+            height,
+            rows,
+            pen: Pen {
+                color: [255; 4],
+                blend_mode: BlendMode::Replace,
+                antialias: false,
+                stroke_width: 1.0,
+                line_cap: LineCap::Butt,
+                line_join: LineJoin::Round,
+            },
+        }
+    }
+
+    pub fn draw(&mut self, command: &Command) {
+        let (top, bottom) = command.rows();
+        if bottom < self.rows.start as f32 || top > self.rows.end as f32 {
+            return;
+        }
+        self.pen = command.pen;
+        let half = self.pen.stroke_width / 2.0;
+        match &command.shape {
+            Shape::Point(pos) => self.draw_point(*pos),
+            Shape::Line(from, to) => self.draw_line(*from, *to),
+            Shape::Curve(start, control, end) => self.draw_curve(*start, *control, *end),
+            Shape::Stroke { points, closed } => self.stroke(points, *closed),
+            &Shape::Circle {
+                center,
+                radius,
+                filled: true,
+            } => self.fill_shape(center - radius, center + radius, true, |pos| {
+                pos.distance(center) - radius
+            }),
+            &Shape::Circle { center, radius, .. } => self.fill_shape(
+                center - radius - half,
+                center + radius + half,
+                true,
+                |pos| (pos.distance(center) - radius).abs() - half,
+            ),
+            &Shape::Dot { center, radius } => self.fill(center - radius, center + radius, |pos| {
+                let t = (1.0 - pos.distance(center) / radius).clamp(0.0, 1.0);
+                t * t * (3.0 - 2.0 * t)
+            }),
+            &Shape::RoundedRect {
+                top_left,
+                bottom_right,
+                radius,
+                filled: true,
+            } => self.fill_shape(top_left, bottom_right, true, |pos| {
+                rounded_rect_distance(pos, top_left, bottom_right, radius)
+            }),
+            &Shape::RoundedRect {
+                top_left,
+                bottom_right,
+                radius,
+                ..
+            } => self.fill_shape(top_left - half, bottom_right + half, true, |pos| {
+                rounded_rect_distance(pos, top_left, bottom_right, radius).abs() - half
+            }),
+            Shape::Polygon(points) => {
+                let Some(&first) = points.first() else {
+                    return;
+                };
+                let min = points.iter().fold(first, |min, point| min.min(*point));
+                let max = points.iter().fold(first, |max, point| max.max(*point));
+                self.fill_shape(min, max, true, |pos| polygon_distance(pos, points));
+            }
+        }
+    }
+
+    fn draw_curve(&mut self, start: Vec2, control: Vec2, end: Vec2) {
+        if self.pen.antialias || self.pen.stroke_width > 1.0 {
+            self.stroke(&Self::flatten(start, control, end), false);
+            return;
+        }
+        let points = start.distance(control) + control.distance(end) + end.distance(start);
+        for i in 1..points as usize {
+            let proportion = i as f32 / points;
+            let path1 = control - start;
+            let point1 = start + path1 * proportion;
+            let path2 = end - control;
+            let point2 = control + path2 * proportion;
+            let path3 = point2 - point1;
+            let point3 = point1 + path3 * proportion;
+            self.draw_point(point3);
+        }
+    }
+
+    fn draw_line(&mut self, from: Vec2, to: Vec2) {
+        if self.pen.stroke_width > 1.0 {
+            self.stroke(&[from, to], false);
+            return;
+        }
+        if self.pen.antialias {
+            self.draw_line_aa(from, to);
+            return;
+        }
+        let delta = to - from;
+        let axis_biggest_distance = (delta.x).abs().max((delta.y).abs()) as usize;
+        let normalized = delta.normalize();
+        for step in 0..axis_biggest_distance {
+            let magnitude = step as f32;
+            let x = from.x + normalized.x * magnitude;
+            let y = from.y + normalized.y * magnitude;
+            self.draw_point(Vec2::new(x, y));
+        }
+    }
+
+    /// A line `stroke_width` wide, pixels blended by how much of them it covers. Like Wu's
+    /// algorithm it walks the longer axis and spreads each step over the pixels across.
+    /// Coordinates name the same pixels as `draw_point`, `(10, 10)` is the middle of
+    /// pixel 10, 10.
+    fn draw_line_aa(&mut self, from: Vec2, to: Vec2) {
+        let steep = (to.y - from.y).abs() > (to.x - from.x).abs();
+        // Walk along x, with x and y swapped for steep lines.
+        let (mut start, mut end) = if steep {
+            (from.yx(), to.yx())
+        } else {
+            (from, to)
+        };
+        if start.x > end.x {
+            std::mem::swap(&mut start, &mut end);
+        }
+        let (start, end) = (start + 0.5, end + 0.5);
+        let length = end.x - start.x;
+        if length <= f32::EPSILON {
+            return;
+        }
+        let gradient = (end.y - start.y) / length;
+        // How far across the walked axis a stroke of `stroke_width` reaches.
+        let half_width = self.pen.stroke_width * (1.0 + gradient * gradient).sqrt() / 2.0;
+        // Only the band's rows are walked along or across.
+        let (columns, rows) = if steep {
+            (self.rows.clone(), 0..self.width)
+        } else {
+            (0..self.width, self.rows.clone())
+        };
+        let first = (start.x.floor().max(0.0) as usize).max(columns.start);
+        let last = (end.x.ceil().max(0.0) as usize).min(columns.end);
+        for column in first..last {
+            // The ends only cover part of their column.
+            let left = (column as f32).max(start.x);
+            let right = (column as f32 + 1.0).min(end.x);
+            let center = start.y + gradient * ((left + right) / 2.0 - start.x);
+            let (top, bottom) = (center - half_width, center + half_width);
+            let Some(range) = Self::clip(top.floor(), bottom.floor(), rows.clone()) else {
+                continue;
+            };
+            for row in range {
+                let covered = bottom.min(row as f32 + 1.0) - top.max(row as f32);
+                let coverage = covered.clamp(0.0, 1.0) * (right - left);
+                if steep {
+                    self.plot(row, column, coverage);
+                } else {
+                    self.plot(column, row, coverage);
+                }
+            }
+        }
+    }
+
+    /// A line through `points`, `stroke_width` wide with `line_cap` at the ends and
+    /// `line_join` at the corners. A `closed` one goes back to the first point.
+    fn stroke(&mut self, points: &[Vec2], closed: bool) {
+        let half = self.pen.stroke_width / 2.0;
+        let mut points = points.to_vec();
+        points.dedup_by(|point, previous| point.distance(*previous) < 1e-3);
+        if closed && points.len() > 2 && points[0].distance(points[points.len() - 1]) < 1e-3 {
+            points.pop();
+        }
+        let Some(&first) = points.first() else {
+            return;
+        };
+        let last = points[points.len() - 1];
+        let mut segments: Vec<(Vec2, Vec2)> = points.windows(2).map(|w| (w[0], w[1])).collect();
+        if closed && points.len() > 2 {
+            segments.push((last, first));
+        }
+        // Everything the stroke is made of, it covers what any of them does.
+        let mut dots = Vec::new();
+        let mut corners = Vec::new();
+        if !closed {
+            match self.pen.line_cap {
+                LineCap::Butt => (),
+                LineCap::Round => dots.extend([first, last]),
+                LineCap::Square => match segments.len() {
+                    0 => segments.push((first - Vec2::X * half, first + Vec2::X * half)),
+                    count => {
+                        let start = &mut segments[0];
+                        start.0 -= (start.1 - start.0).normalize() * half;
+                        let end = &mut segments[count - 1];
+                        end.1 += (end.1 - end.0).normalize() * half;
+                    }
+                },
+            }
+        }
+        let joins = if closed {
+            segments.len()
+        } else {
+            segments.len().saturating_sub(1)
+        };
+        for idx in 0..joins {
+            let (before, after) = (segments[idx], segments[(idx + 1) % segments.len()]);
+            let corner = before.1;
+            match self.pen.line_join {
+                LineJoin::Round => dots.push(corner),
+                join => corners.extend(Self::join(corner, before.0, after.1, half, join)),
+            }
+        }
+        // Square caps reach past `half` diagonally, miters up to their limit.
+        let reach = match self.pen.line_join {
+            LineJoin::Miter => MITER_LIMIT * half,
+            _ => 1.5 * half,
+        };
+        // Round joins overlap both segments, the wedges of the others only touch them.
+        let exact = corners.is_empty();
+        let min = points.iter().fold(first, |min, point| min.min(*point)) - reach;
+        let max = points.iter().fold(first, |max, point| max.max(*point)) + reach;
+        self.fill_shape(min, max, exact, |pos| {
+            let segments = segments
+                .iter()
+                .map(|(start, end)| segment_distance(pos, *start, *end, half));
+            let dots = dots.iter().map(|dot| pos.distance(*dot) - half);
+            let corners = corners.iter().map(|corner| polygon_distance(pos, corner));
+            segments.chain(dots).chain(corners).fold(f32::MAX, f32::min)
+        });
+    }
+
+    /// The wedge filling the outside of a bend at `corner`, empty for `Round` joins.
+    fn join(corner: Vec2, from: Vec2, to: Vec2, half: f32, join: LineJoin) -> Option<Vec<Vec2>> {
+        let (before, after) = ((corner - from).normalize(), (to - corner).normalize());
+        if before.perp_dot(after).abs() < 1e-4 {
+            return None;
+        }
+        // The outer edges are on the side the stroke turns away from.
+        let side = if before.perp().dot(after) > 0.0 {
+            -half
+        } else {
+            half
+        };
+        let (outer_before, outer_after) =
+            (corner + before.perp() * side, corner + after.perp() * side);
+        let bevel = vec![corner, outer_before, outer_after];
+        if join != LineJoin::Miter {
+            return Some(bevel);
+        }
+        let direction = (before.perp() + after.perp()).normalize() * side.signum();
+        let length = half / direction.dot(before.perp() * side.signum());
+        if length > MITER_LIMIT * half {
+            return Some(bevel);
+        }
+        Some(vec![
+            corner,
+            outer_before,
+            corner + direction * length,
+            outer_after,
+        ])
+    }
+
+    /// Points along a quadratic Bézier curve a few pixels apart.
+    fn flatten(start: Vec2, control: Vec2, end: Vec2) -> Vec<Vec2> {
+        let length = start.distance(control) + control.distance(end);
+        let segments = (length / 4.0).ceil().max(1.0) as usize;
+        (0..=segments)
+            .map(|segment| {
+                let t = segment as f32 / segments as f32;
+                start.lerp(control, t).lerp(control.lerp(end, t), t)
+            })
+            .collect()
+    }
+
+    /// Fills the shape `distance` describes: the distance from a point to its edge,
+    /// negative inside. Unless it's `exact` it may be too small inside, as a union of
+    /// shapes' distances is along the seams where they touch without overlapping, so
+    /// pixels close to an edge are antialiased by sampling which points in them are inside.
+    fn fill_shape(&mut self, min: Vec2, max: Vec2, exact: bool, distance: impl Fn(Vec2) -> f32) {
+        // Farther than half a pixel diagonal the whole pixel is on one side.
+        const WHOLE_PIXEL: f32 = 0.71;
+        const SAMPLES: usize = 4;
+        let antialias = self.pen.antialias;
+        let outside = if antialias { WHOLE_PIXEL } else { 0.0 };
+        let Some((columns, rows)) = self.pixels_around(min, max) else {
+            return;
+        };
+        for y in rows {
+            let mut x = *columns.start();
+            while x <= *columns.end() {
+                let pos = Vec2::new(x as f32, y as f32);
+                let center = distance(pos);
+                if center > outside {
+                    // Distance outside is exact, so the next pixels closer than it are out too.
+                    x += (center - outside - 1e-3).clamp(0.0, self.width as f32) as usize + 1;
+                    continue;
+                }
+                let coverage = if !antialias || center <= -WHOLE_PIXEL {
+                    1.0
+                } else if exact {
+                    (0.5 - center).clamp(0.0, 1.0)
+                } else {
+                    let inside = (0..SAMPLES * SAMPLES)
+                        .filter(|sample| {
+                            let offset =
+                                Vec2::new((sample % SAMPLES) as f32, (sample / SAMPLES) as f32);
+                            distance(pos + (offset + 0.5) / SAMPLES as f32 - 0.5) <= 0.0
+                        })
+                        .count();
+                    inside as f32 / (SAMPLES * SAMPLES) as f32
+                };
+                if coverage > 0.0 {
+                    self.plot(x, y, coverage);
+                }
+                x += 1;
+            }
+        }
+    }
+
+    /// Blends the pen color into the pixels from `min` to `max` by their `coverage` in 0..=1.
+    fn fill(&mut self, min: Vec2, max: Vec2, coverage: impl Fn(Vec2) -> f32) {
+        let Some((columns, rows)) = self.pixels_around(min, max) else {
+            return;
+        };
+        for y in rows {
+            for x in columns.clone() {
+                let coverage = coverage(Vec2::new(x as f32, y as f32));
+                if coverage > 0.0 {
+                    self.plot(x, y, coverage);
+                }
+            }
+        }
+    }
+
+    /// Columns and rows in the band of the pixels from `min` to `max`, and a pixel around
+    /// them.
+    fn pixels_around(
+        &self,
+        min: Vec2,
+        max: Vec2,
+    ) -> Option<(RangeInclusive<usize>, RangeInclusive<usize>)> {
+        let columns = Self::clip((min.x - 1.0).floor(), (max.x + 1.0).ceil(), 0..self.width)?;
+        let rows = Self::clip(
+            (min.y - 1.0).floor(),
+            (max.y + 1.0).ceil(),
+            self.rows.clone(),
+        )?;
+        Some((columns, rows))
+    }
+
+    /// Pixels from `start` to `end` inclusive that are within `range`.
+    fn clip(start: f32, end: f32, range: Range<usize>) -> Option<RangeInclusive<usize>> {
+        if end < range.start as f32 || start >= range.end as f32 || end < start {
+            return None;
+        }
+        Some(start.max(range.start as f32) as usize..=(end as usize).min(range.end - 1))
+    }
+
+    fn draw_point(&mut self, pos: Vec2) {
+        if pos.x >= self.width as f32 || pos.x < 0.0 || pos.y >= self.height as f32 || pos.y < 0.0 {
+            return;
+        }
+        let (x, y) = (pos.x as usize, pos.y as usize);
+        if self.rows.contains(&y) {
+            self.plot(x, y, 1.0);
+        }
+    }
+
+    /// Blends the pen color into a pixel of the band, its alpha scaled by `coverage` in
+    /// 0..=1.
+    fn plot(&mut self, x: usize, y: usize, coverage: f32) {
+        let idx = (x + (y - self.rows.start) * self.width) * 4;
+        blend(
+            &mut self.pixels[idx..idx + 4],
+            self.pen.color,
+            self.pen.blend_mode,
+            coverage,
+        );
+    }
+}
+
+/// Blends `color` into an RGBA `pixel`, its alpha scaled by `coverage` in 0..=1.
+pub fn blend(pixel: &mut [u8], color: [u8; 4], mode: BlendMode, coverage: f32) {
+    if mode == BlendMode::Replace && coverage >= 1.0 {
+        pixel.copy_from_slice(&color);
+        return;
+    }
+    let alpha = color[3] as f32 / 255.0 * coverage.clamp(0.0, 1.0);
+    if alpha <= 0.0 {
+        return;
+    }
+    let dst_alpha = pixel[3] as f32 / 255.0;
+    let out_alpha = match mode {
+        BlendMode::Add => (dst_alpha + alpha).min(1.0),
+        BlendMode::Multiply => dst_alpha,
+        BlendMode::Lighten => dst_alpha.max(alpha),
+        _ => alpha + dst_alpha * (1.0 - alpha),
+    };
+    for channel in 0..3 {
+        let src = color[channel] as f32 / 255.0;
+        let dst = pixel[channel] as f32 / 255.0;
+        let out = match mode {
+            BlendMode::Blend if out_alpha > 0.0 => {
+                (src * alpha + dst * dst_alpha * (1.0 - alpha)) / out_alpha
+            }
+            BlendMode::Blend => 0.0,
+            BlendMode::Replace | BlendMode::Over => src * alpha + dst * (1.0 - alpha),
+            BlendMode::Add => dst + src * alpha,
+            BlendMode::Screen => dst + src * alpha - dst * src * alpha,
+            BlendMode::Multiply => dst * (1.0 - alpha + src * alpha),
+            BlendMode::Lighten => dst.max(src * alpha),
+        };
+        pixel[channel] = (out.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+    pixel[3] = (out_alpha * 255.0).round() as u8;
+}
+
+/// Distance from `pos` to a line from `start` to `end` that is `2 * half` wide, with flat
+/// ends, negative inside.
+fn segment_distance(pos: Vec2, start: Vec2, end: Vec2, half: f32) -> f32 {
+    let length = start.distance(end);
+    if length < 1e-6 {
+        return f32::MAX;
+    }
+    let along = (end - start) / length;
+    let offset = pos - (start + end) / 2.0;
+    let outside = Vec2::new(
+        offset.dot(along).abs() - length / 2.0,
+        offset.dot(along.perp()).abs() - half,
+    );
+    outside.max(Vec2::ZERO).length() + outside.x.max(outside.y).min(0.0)
+}
+
+fn rounded_rect_distance(pos: Vec2, top_left: Vec2, bottom_right: Vec2, radius: f32) -> f32 {
+    let half_size = (bottom_right - top_left) / 2.0;
+    let radius = radius.min(half_size.x).min(half_size.y).max(0.0);
+    let offset = (pos - (top_left + bottom_right) / 2.0).abs() - half_size + radius;
+    offset.max(Vec2::ZERO).length() + offset.x.max(offset.y).min(0.0) - radius
+}
+
+/// Distance from `pos` to the outline of a polygon, negative inside by the even-odd rule.
+fn polygon_distance(pos: Vec2, points: &[Vec2]) -> f32 {
+    let mut nearest = f32::MAX;
+    let mut inside = false;
+    for (idx, &start) in points.iter().enumerate() {
+        let end = points[(idx + 1) % points.len()];
+        let edge = end - start;
+        let to_pos = pos - start;
+        let along = (to_pos.dot(edge) / edge.length_squared().max(1e-12)).clamp(0.0, 1.0);
+        nearest = nearest.min((to_pos - edge * along).length_squared());
+        // Crossings of a ray going right from `pos`.
+        if (start.y > pos.y) != (end.y > pos.y)
+            && pos.x < start.x + (pos.y - start.y) / (end.y - start.y) * edge.x
+        {
+            inside = !inside;
+        }
+    }
+    if inside {
+        -nearest.sqrt()
+    } else {
+        nearest.sqrt()
+    }
+}
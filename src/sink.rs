@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+use memmap2::MmapMut;
+use rs_piano_midi::{gif, png, raster, svg};
+
+use crate::config::{Config, ImageSequence};
+
+/// A finished frame, RGBA8 rows top to bottom.
+pub struct Frame<'a> {
+    pub rgba: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    /// Frames drawn before this one.
+    pub index: usize,
+    /// What was drawn on `background` for this frame, without trails and effects.
+    pub commands: &'a [raster::Command],
+    pub background: [u8; 4],
+}
+
+/// Somewhere frames go once they are drawn. A sink that fails is dropped, the others
+/// keep going.
+pub trait FrameSink {
+    /// Names the sink in error messages.
+    fn name(&self) -> String;
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()>;
+
+    /// True once the sink takes no more frames.
+    fn done(&self) -> bool {
+        false
+    }
+
+    /// Flushes and closes the output, after the last frame.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Frames shared with a viewer through a memory mapped file, only the latest one is kept.
+pub struct ImageSink {
+    path: String,
+    mmap: MmapMut,
+}
+
+impl ImageSink {
+    pub fn new(path: &str, width: usize, height: usize) -> io::Result<Self> {
+        let map = || {
+            let file = File::options()
+                .create(true)
+                .read(true)
+                .write(true)
+                .truncate(false)
+                .open(path)?;
+            file.set_len((width * height * 4) as u64)?;
+            unsafe { MmapMut::map_mut(&file) }
+        };
+        let mmap = map().map_err(|err| with_path(path, err))?;
+        // Locked pages never go to swap, but it's only worth a warning when the limit is low.
+        if let Err(err) = mmap.lock() {
+            eprintln!("{path}: could not lock the image in memory: {err}");
+        }
+        Ok(Self {
+            path: path.into(),
+            mmap,
+        })
+    }
+}
+
+impl FrameSink for ImageSink {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        if frame.rgba.len() != self.mmap.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame size changed",
+            ));
+        }
+        self.mmap.copy_from_slice(frame.rgba);
+        Ok(())
+    }
+}
+
+/// Audio muxed into a recording.
+pub enum AudioTrack {
+    /// The user's file.
+    File(String),
+    /// Synthesized for the recording, deleted when it's dropped.
+    Temporary(String),
+}
+
+impl AudioTrack {
+    fn path(&self) -> &str {
+        match self {
+            AudioTrack::File(path) | AudioTrack::Temporary(path) => path,
+        }
+    }
+}
+
+impl Drop for AudioTrack {
+    fn drop(&mut self) {
+        if let AudioTrack::Temporary(path) = self {
+            // Nothing to do about it if it's gone already.
+            let _ = std::fs::remove_file(&*path);
+        }
+    }
+}
+
+/// Encodes the frames to a video with ffmpeg, muxing in `audio` if there is one.
+pub struct FfmpegSink {
+    ffmpeg: Child,
+    /// Kept until the recording is done with it.
+    _audio: Option<AudioTrack>,
+}
+
+impl FfmpegSink {
+    pub fn new(config: &Config, audio: Option<AudioTrack>) -> io::Result<Self> {
+        let ffmpeg_command = "/usr/bin/ffmpeg";
+        let video_in = format!(
+            "-y -f rawvideo -vcodec rawvideo -s {}x{} -pix_fmt rgba -r {} -i -",
+            config.width, config.height, config.fps
+        );
+        let video_out = "-vcodec h264 -pix_fmt yuv420p -crf 15";
+        // The recording starts at `start`, so does its audio.
+        let offset = config.soundtrack.offset + config.start as f64;
+        let mut args: Vec<String> = video_in.split(' ').map(String::from).collect();
+        match &audio {
+            Some(audio) => {
+                if offset >= 0.0 {
+                    args.extend(["-ss".into(), offset.to_string()]);
+                } else {
+                    args.extend(["-itsoffset".into(), (-offset).to_string()]);
+                }
+                args.extend(["-i".into(), audio.path().into()]);
+                // Silence pads audio that ends before the video, e.g. during `--tail`,
+                // the video decides how long the recording is.
+                let mux = "-map 0:v -map 1:a -af apad -acodec aac -b:a 192k -shortest";
+                args.extend(mux.split(' ').map(String::from));
+            }
+            None => args.push("-an".into()),
+        }
+        args.extend(video_out.split(' ').map(String::from));
+        args.push(config.output.clone());
+        let ffmpeg = Command::new(ffmpeg_command)
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| with_path(ffmpeg_command, err))?;
+        Ok(Self {
+            ffmpeg,
+            _audio: audio,
+        })
+    }
+}
+
+impl FrameSink for FfmpegSink {
+    fn name(&self) -> String {
+        "ffmpeg".into()
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        match self.ffmpeg.stdin.as_mut() {
+            Some(stdin) => stdin.write_all(frame.rgba),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Closing stdin lets ffmpeg finish the file.
+        drop(self.ffmpeg.stdin.take());
+        let status = self.ffmpeg.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("exited with {status}")))
+        }
+    }
+}
+
+/// Every frame from `start` to `end` as a numbered PNG in a directory.
+pub struct PngSink {
+    dir: PathBuf,
+    frames: ImageSequence,
+    /// Index of the frame after the last one written.
+    next_index: usize,
+    straight: Vec<u8>,
+}
+
+impl PngSink {
+    pub fn new(dir: &str, frames: &ImageSequence) -> io::Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|err| with_path(dir, err))?;
+        Ok(Self {
+            dir: dir.into(),
+            frames: frames.clone(),
+            next_index: 0,
+            straight: Vec::new(),
+        })
+    }
+}
+
+impl FrameSink for PngSink {
+    fn name(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let past_end = self.frames.end.is_some_and(|end| frame.index > end);
+        self.next_index = frame.index + 1;
+        if frame.index < self.frames.start || past_end {
+            return Ok(());
+        }
+        // The canvas keeps colors premultiplied by alpha, PNG wants them straight.
+        self.straight.clear();
+        self.straight.extend_from_slice(frame.rgba);
+        for pixel in self.straight.chunks_exact_mut(4) {
+            let alpha = pixel[3] as u32;
+            if alpha > 0 && alpha < 255 {
+                for channel in &mut pixel[..3] {
+                    *channel = (*channel as u32 * 255 / alpha).min(255) as u8;
+                }
+            }
+        }
+        let path = self.dir.join(format!("frame-{:06}.png", frame.index));
+        let mut file = BufWriter::new(File::create(&path)?);
+        png::write(&mut file, frame.width, frame.height, &self.straight)
+    }
+
+    fn done(&self) -> bool {
+        self.frames.end.is_some_and(|end| self.next_index > end)
+    }
+}
+
+/// What every frame from `start` to `end` draws, as a numbered SVG in a directory.
+pub struct SvgSink {
+    dir: PathBuf,
+    frames: ImageSequence,
+    /// Index of the frame after the last one written.
+    next_index: usize,
+}
+
+impl SvgSink {
+    pub fn new(dir: &str, frames: &ImageSequence) -> io::Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|err| with_path(dir, err))?;
+        Ok(Self {
+            dir: dir.into(),
+            frames: frames.clone(),
+            next_index: 0,
+        })
+    }
+}
+
+impl FrameSink for SvgSink {
+    fn name(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let past_end = self.frames.end.is_some_and(|end| frame.index > end);
+        self.next_index = frame.index + 1;
+        if frame.index < self.frames.start || past_end {
+            return Ok(());
+        }
+        let path = self.dir.join(format!("frame-{:06}.svg", frame.index));
+        let mut file = BufWriter::new(File::create(&path)?);
+        svg::write(
+            &mut file,
+            frame.width,
+            frame.height,
+            frame.background,
+            frame.commands,
+        )
+    }
+
+    fn done(&self) -> bool {
+        self.frames.end.is_some_and(|end| self.next_index > end)
+    }
+}
+
+/// The frames back to back as raw RGBA8, e.g. for piping into other tools. What's needed
+/// to read them back is written next to them into `<path>.txt` right away, so a preview
+/// that is stopped still leaves a readable file, and again with the frame count once
+/// they are done.
+pub struct RawSink {
+    path: String,
+    file: BufWriter<File>,
+    fps: f64,
+    size: (usize, usize),
+    frames: usize,
+}
+
+impl RawSink {
+    pub fn new(path: &str, width: usize, height: usize, fps: f64) -> io::Result<Self> {
+        let sink = Self {
+            path: path.into(),
+            file: BufWriter::new(File::create(path).map_err(|err| with_path(path, err))?),
+            fps,
+            size: (width, height),
+            frames: 0,
+        };
+        sink.write_sidecar(None)?;
+        Ok(sink)
+    }
+
+    fn write_sidecar(&self, frames: Option<usize>) -> io::Result<()> {
+        let (width, height) = self.size;
+        let mut header = format!(
+            "width = {width}\n\
+             height = {height}\n\
+             fps = {fps}\n\
+             pixel-format = rgba\n",
+            fps = self.fps,
+        );
+        if let Some(frames) = frames {
+            header += &format!("frames = {frames}\n");
+        }
+        header += &format!(
+            "# ffmpeg -f rawvideo -pix_fmt rgba -s {width}x{height} -r {fps} -i {path} video.mp4\n",
+            fps = self.fps,
+            path = self.path,
+        );
+        let sidecar = format!("{}.txt", self.path);
+        std::fs::write(&sidecar, header).map_err(|err| with_path(&sidecar, err))
+    }
+}
+
+impl FrameSink for RawSink {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        self.frames += 1;
+        self.file.write_all(frame.rgba)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.write_sidecar(Some(self.frames))
+    }
+}
+
+/// Uncompressed YUV4MPEG2 video, which most players and encoders read directly. The
+/// frames are converted to 4:2:0 with BT.601 studio range, dropping alpha like ffmpeg does.
+pub struct Y4mSink {
+    path: String,
+    file: BufWriter<File>,
+    planes: Vec<u8>,
+}
+
+impl Y4mSink {
+    pub fn new(path: &str, width: usize, height: usize, fps: f64) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path).map_err(|err| with_path(path, err))?);
+        // Y4M frame rates are fractions, three decimals cover 29.97 and friends.
+        let (rate, scale) = if fps.fract() == 0.0 {
+            (fps as u64, 1)
+        } else {
+            ((fps * 1000.0).round() as u64, 1000)
+        };
+        writeln!(
+            file,
+            "YUV4MPEG2 W{width} H{height} F{rate}:{scale} Ip A1:1 C420jpeg"
+        )?;
+        Ok(Self {
+            path: path.into(),
+            file,
+            planes: Vec::new(),
+        })
+    }
+
+    /// Fills `planes` with the Y plane, then the quarter size U and V planes.
+    fn convert(&mut self, frame: &Frame) {
+        let (width, height) = (frame.width, frame.height);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        let luma = width * height;
+        let chroma = chroma_width * chroma_height;
+        self.planes.clear();
+        self.planes.resize(luma + chroma * 2, 0);
+        let rgb = |x: usize, y: usize| {
+            let idx = (x + y * width) * 4;
+            let pixel = &frame.rgba[idx..idx + 3];
+            [pixel[0] as i32, pixel[1] as i32, pixel[2] as i32]
+        };
+        for y in 0..height {
+            for x in 0..width {
+                let [r, g, b] = rgb(x, y);
+                self.planes[x + y * width] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            }
+        }
+        for y in 0..chroma_height {
+            for x in 0..chroma_width {
+                // Average the 2x2 block, the last row and column may be cut in half.
+                let mut sum = [0; 3];
+                let mut count = 0;
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let (px, py) = (x * 2 + dx, y * 2 + dy);
+                    if px < width && py < height {
+                        let pixel = rgb(px, py);
+                        (0..3).for_each(|channel| sum[channel] += pixel[channel]);
+                        count += 1;
+                    }
+                }
+                let [r, g, b] = sum.map(|channel| channel / count);
+                let idx = x + y * chroma_width;
+                self.planes[luma + idx] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                self.planes[luma + chroma + idx] =
+                    (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+            }
+        }
+    }
+}
+
+impl FrameSink for Y4mSink {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        self.convert(frame);
+        self.file.write_all(b"FRAME\n")?;
+        self.file.write_all(&self.planes)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// An animated GIF over the palette colors on the background, for sharing short clips. Only every
+/// few frames are kept to get close to `fps`, as GIF players don't keep up with 30fps.
+pub struct GifSink {
+    path: String,
+    encoder: Option<gif::Encoder<BufWriter<File>>>,
+    /// Black first, then the background if it's opaque, then the palette.
+    colors: Vec<[u8; 3]>,
+    /// Palette index of every pixel value seen so far.
+    lookup: HashMap<[u8; 4], u8>,
+    /// Every `every`th frame is kept.
+    every: usize,
+    /// Seconds between two kept frames.
+    step: f64,
+    kept: usize,
+    pixels: Vec<u8>,
+}
+
+impl GifSink {
+    pub fn new(path: &str, config: &Config) -> io::Result<Self> {
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidInput, err);
+        let width = u16::try_from(config.width).map_err(|_| invalid("too wide for a gif"))?;
+        let height = u16::try_from(config.height).map_err(|_| invalid("too high for a gif"))?;
+        let mut colors = vec![[0, 0, 0]];
+        // Anything less than opaque shows over black, close to the colors there already.
+        let [r, g, b, alpha] = config.background;
+        if alpha == 255 && [r, g, b] != [0, 0, 0] {
+            colors.push([r, g, b]);
+        }
+        colors.extend(config.palette.iter().map(|[r, g, b, _]| [*r, *g, *b]));
+        colors.truncate(256);
+        let file = BufWriter::new(File::create(path).map_err(|err| with_path(path, err))?);
+        let repeat = match config.gif.plays {
+            0 => Some(0),
+            1 => None,
+            plays => Some(plays - 1),
+        };
+        let encoder = gif::Encoder::new(file, width, height, &colors, repeat)?;
+        let every = (config.fps / config.gif.fps).round().max(1.0) as usize;
+        Ok(Self {
+            path: path.into(),
+            encoder: Some(encoder),
+            colors,
+            lookup: HashMap::new(),
+            every,
+            step: every as f64 / config.fps,
+            kept: 0,
+            pixels: Vec::new(),
+        })
+    }
+
+    /// The closest color to `rgba` drawn over black, which for premultiplied colors is
+    /// just leaving alpha out.
+    fn index_of(&mut self, rgba: [u8; 4]) -> u8 {
+        let colors = &self.colors;
+        *self.lookup.entry(rgba).or_insert_with(|| {
+            let rgb = [0, 1, 2].map(|channel| rgba[channel] as i32);
+            let distance = |color: &[u8; 3]| -> i32 {
+                (0..3)
+                    .map(|channel| (color[channel] as i32 - rgb[channel]).pow(2))
+                    .sum()
+            };
+            (0..colors.len())
+                .min_by_key(|idx| distance(&colors[*idx]))
+                .unwrap_or(0) as u8
+        })
+    }
+}
+
+impl FrameSink for GifSink {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        if !frame.index.is_multiple_of(self.every) {
+            return Ok(());
+        }
+        let mut pixels = std::mem::take(&mut self.pixels);
+        pixels.clear();
+        for pixel in frame.rgba.chunks_exact(4) {
+            pixels.push(self.index_of([pixel[0], pixel[1], pixel[2], pixel[3]]));
+        }
+        // Delays are whole hundredths, rounding the running time keeps them from drifting.
+        let centiseconds = |frames: usize| (frames as f64 * self.step * 100.0).round() as u64;
+        let delay = (centiseconds(self.kept + 1) - centiseconds(self.kept)) as u16;
+        self.kept += 1;
+        let result = match &mut self.encoder {
+            Some(encoder) => encoder.write_frame(&pixels, delay),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        };
+        self.pixels = pixels;
+        result
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.encoder.take() {
+            Some(encoder) => encoder.finish().map(drop),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Throws the frames away, to time drawing alone.
+pub struct NullSink;
+
+impl FrameSink for NullSink {
+    fn name(&self) -> String {
+        "null".into()
+    }
+
+    fn write(&mut self, _frame: &Frame) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn with_path(path: &str, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("{path}: {err}"))
+}
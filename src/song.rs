@@ -1,4 +1,4 @@
-pub const NOTES: [(f32, u8); 8930] = [
+pub static NOTES: [(f32, u8); 8930] = [
     (1.283f32, 74u8),
     (1.283f32, 26u8),
     (1.283f32, 65u8),
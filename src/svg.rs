@@ -0,0 +1,227 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use glam::Vec2;
+
+use crate::canvas::{BlendMode, LineCap, LineJoin};
+use crate::raster::{Command, Pen, Shape};
+
+/// Writes a display list as an SVG image of `width` by `height` pixels on `background`,
+/// which is premultiplied and left out when transparent.
+pub fn write(
+    out: &mut impl Write,
+    width: usize,
+    height: usize,
+    background: [u8; 4],
+    commands: &[Command],
+) -> io::Result<()> {
+    let mut svg = String::new();
+    // Writing to a string can't fail.
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    );
+    let dot_colors: BTreeSet<[u8; 4]> = commands
+        .iter()
+        .filter(|command| matches!(command.shape, Shape::Dot { .. }))
+        .map(|command| command.pen.color)
+        .collect();
+    if !dot_colors.is_empty() {
+        svg.push_str("<defs>\n");
+        for color in &dot_colors {
+            // The smoothstep falloff of the raster dots, at a few points.
+            let _ = writeln!(svg, r#"<radialGradient id="{}">"#, dot_id(*color));
+            for offset in [0.0f32, 0.25, 0.5, 0.75, 1.0] {
+                let t = 1.0 - offset;
+                let opacity = t * t * (3.0 - 2.0 * t) * color[3] as f32 / 255.0;
+                let _ = writeln!(
+                    svg,
+                    r#"<stop offset="{offset}" stop-color="{}" stop-opacity="{opacity:.3}"/>"#,
+                    hex(*color)
+                );
+            }
+            svg.push_str("</radialGradient>\n");
+        }
+        svg.push_str("</defs>\n");
+    }
+    if background[3] > 0 {
+        let alpha = background[3] as f32 / 255.0;
+        let straight = background.map(|channel| (channel as f32 / alpha).min(255.0) as u8);
+        let _ = writeln!(
+            svg,
+            r#"<rect width="{width}" height="{height}" fill="{}"{}/>"#,
+            hex(straight),
+            opacity("fill-opacity", background[3])
+        );
+    }
+    // The canvas puts pixel centers on whole coordinates, SVG puts their corners there.
+    svg.push_str("<g transform=\"translate(0.5 0.5)\">\n");
+    for command in commands {
+        element(&mut svg, command);
+    }
+    svg.push_str("</g>\n</svg>\n");
+    out.write_all(svg.as_bytes())?;
+    out.flush()
+}
+
+fn element(svg: &mut String, command: &Command) {
+    let pen = &command.pen;
+    let fill = format!(
+        r#"fill="{}"{}"#,
+        hex(pen.color),
+        opacity("fill-opacity", pen.color[3])
+    );
+    let _ = match &command.shape {
+        Shape::Point(pos) => {
+            let corner = pos.floor() - 0.5;
+            writeln!(
+                svg,
+                r#"<rect x="{}" y="{}" width="1" height="1" {fill}{}/>"#,
+                corner.x,
+                corner.y,
+                style(pen)
+            )
+        }
+        Shape::Line(from, to) => writeln!(
+            svg,
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" {}/>"#,
+            from.x,
+            from.y,
+            to.x,
+            to.y,
+            stroke(pen, pen.stroke_width > 1.0)
+        ),
+        Shape::Curve(start, control, end) => writeln!(
+            svg,
+            r#"<path d="M{} {} Q{} {} {} {}" fill="none" {}/>"#,
+            start.x,
+            start.y,
+            control.x,
+            control.y,
+            end.x,
+            end.y,
+            stroke(pen, true)
+        ),
+        Shape::Stroke { points, closed } => writeln!(
+            svg,
+            r#"<{} points="{}" fill="none" {}/>"#,
+            if *closed { "polygon" } else { "polyline" },
+            points_attribute(points),
+            stroke(pen, true)
+        ),
+        Shape::Circle {
+            center,
+            radius,
+            filled,
+        } => {
+            let paint = if *filled { fill } else { stroke(pen, true) };
+            writeln!(
+                svg,
+                r#"<circle cx="{}" cy="{}" r="{radius}" {paint}/>"#,
+                center.x, center.y
+            )
+        }
+        Shape::Dot { center, radius } => writeln!(
+            svg,
+            r#"<circle cx="{}" cy="{}" r="{radius}" fill="url(#{})"{}/>"#,
+            center.x,
+            center.y,
+            dot_id(pen.color),
+            style(pen)
+        ),
+        Shape::RoundedRect {
+            top_left,
+            bottom_right,
+            radius,
+            filled,
+        } => {
+            let paint = if *filled { fill } else { stroke(pen, true) };
+            let size = *bottom_right - *top_left;
+            writeln!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="{}" rx="{radius}" {paint}/>"#,
+                top_left.x, top_left.y, size.x, size.y
+            )
+        }
+        Shape::Polygon(points) => writeln!(
+            svg,
+            r#"<polygon points="{}" fill-rule="evenodd" {fill}/>"#,
+            points_attribute(points)
+        ),
+    };
+}
+
+/// Stroke attributes of `pen`. Thin lines on the canvas have flat ends and no joins to
+/// speak of, `shaped` takes the caps and joins of the pen instead.
+fn stroke(pen: &Pen, shaped: bool) -> String {
+    let mut attributes = format!(
+        r#"stroke="{}"{} stroke-width="{}""#,
+        hex(pen.color),
+        opacity("stroke-opacity", pen.color[3]),
+        pen.stroke_width
+    );
+    if shaped {
+        let cap = match pen.line_cap {
+            LineCap::Butt => "butt",
+            LineCap::Round => "round",
+            LineCap::Square => "square",
+        };
+        let join = match pen.line_join {
+            LineJoin::Miter => r#"miter" stroke-miterlimit="4"#,
+            LineJoin::Round => "round",
+            LineJoin::Bevel => "bevel",
+        };
+        let _ = write!(
+            attributes,
+            r#" stroke-linecap="{cap}" stroke-linejoin="{join}""#
+        );
+    }
+    attributes + &style(pen)
+}
+
+/// How the shape blends and whether its edges are smooth.
+fn style(pen: &Pen) -> String {
+    let mut attributes = String::new();
+    let mode = match pen.blend_mode {
+        BlendMode::Replace | BlendMode::Blend | BlendMode::Over => None,
+        BlendMode::Add => Some("plus-lighter"),
+        BlendMode::Screen => Some("screen"),
+        BlendMode::Multiply => Some("multiply"),
+        BlendMode::Lighten => Some("lighten"),
+    };
+    if let Some(mode) = mode {
+        let _ = write!(attributes, r#" style="mix-blend-mode:{mode}""#);
+    }
+    if !pen.antialias {
+        attributes.push_str(r#" shape-rendering="crispEdges""#);
+    }
+    attributes
+}
+
+fn points_attribute(points: &[Vec2]) -> String {
+    points
+        .iter()
+        .map(|point| format!("{},{}", point.x, point.y))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn opacity(attribute: &str, alpha: u8) -> String {
+    if alpha == 255 {
+        String::new()
+    } else {
+        format!(r#" {attribute}="{:.3}""#, alpha as f32 / 255.0)
+    }
+}
+
+fn hex(color: [u8; 4]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+fn dot_id(color: [u8; 4]) -> String {
+    format!(
+        "dot-{:02x}{:02x}{:02x}{:02x}",
+        color[0], color[1], color[2], color[3]
+    )
+}
@@ -0,0 +1,184 @@
+use std::f64::consts::TAU;
+
+use crate::midi::Note;
+
+/// Loudness and decay rate (per second) of the first partials, roughly a struck string.
+const PARTIALS: [(f32, f32); 6] = [
+    (1.0, 0.8),
+    (0.5, 1.3),
+    (0.3, 1.8),
+    (0.2, 2.4),
+    (0.1, 3.0),
+    (0.05, 3.7),
+];
+const ATTACK: f32 = 0.005;
+const RELEASE: f32 = 0.15;
+/// Headroom so a handful of loud voices at once don't clip.
+const MASTER_GAIN: f32 = 0.15;
+
+/// Plays a note timeline as mono samples in -1..=1. The output only depends on the
+/// notes and the sample rate, so rendering is deterministic.
+pub struct Synth {
+    notes: Vec<Note>,
+    next_note: usize,
+    voices: Vec<Voice>,
+    sample_rate: u32,
+    /// Samples rendered so far.
+    position: u64,
+}
+
+impl Synth {
+    /// `notes` have to be ordered by start, as `Song::notes` returns them.
+    pub fn new(notes: &[Note], sample_rate: u32) -> Self {
+        Self {
+            notes: notes.to_vec(),
+            next_note: 0,
+            voices: Vec::new(),
+            sample_rate,
+            position: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Song time of the next sample to be rendered.
+    pub fn time(&self) -> f64 {
+        self.position as f64 / self.sample_rate as f64
+    }
+
+    /// True once every note has started and faded out.
+    pub fn finished(&self) -> bool {
+        self.next_note == self.notes.len() && self.voices.is_empty()
+    }
+
+    /// Continues from `time`. Notes that started earlier are not picked up again.
+    pub fn seek(&mut self, time: f64) {
+        self.position = self.sample_of(time);
+        self.voices.clear();
+        self.next_note = self
+            .notes
+            .partition_point(|note| (note.start as f64) < time);
+    }
+
+    pub fn render(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            while let Some(note) = self.notes.get(self.next_note) {
+                if self.sample_of(note.start as f64) > self.position {
+                    break;
+                }
+                self.voices.push(Voice::new(note, self.sample_rate));
+                self.next_note += 1;
+            }
+            let sample_rate = self.sample_rate;
+            let mix: f32 = self
+                .voices
+                .iter_mut()
+                .map(|voice| voice.next(sample_rate))
+                .sum();
+            // Soft clip instead of wrapping around when many notes pile up.
+            *sample = (mix * MASTER_GAIN).tanh();
+            self.position += 1;
+        }
+        let sample_rate = self.sample_rate;
+        self.voices.retain(|voice| !voice.finished(sample_rate));
+    }
+
+    /// Renders up to, but not including, the sample at `time` seconds.
+    pub fn render_until(&mut self, time: f64) -> Vec<f32> {
+        let end = self.sample_of(time).max(self.position);
+        let mut samples = vec![0.0; (end - self.position) as usize];
+        self.render(&mut samples);
+        samples
+    }
+
+    /// Renders until every note has faded out, then on to the next multiple of
+    /// `1 / fps` so the track is exactly as long as a video of the song.
+    pub fn render_song(&mut self, fps: f64) -> Vec<f32> {
+        let mut samples = Vec::new();
+        while !self.finished() {
+            samples.extend(self.render_until(self.time() + 1.0));
+        }
+        let frames = (self.time() * fps).ceil();
+        samples.extend(self.render_until(frames / fps));
+        samples
+    }
+
+    fn sample_of(&self, time: f64) -> u64 {
+        (time.max(0.0) * self.sample_rate as f64).round() as u64
+    }
+}
+
+struct Voice {
+    partials: Vec<Partial>,
+    amplitude: f32,
+    /// Samples since the note started.
+    age: u64,
+    /// Length of the held note in samples, the release starts after it.
+    length: u64,
+}
+
+/// A decaying sine, advanced one sample at a time by rotating `(re, im)`.
+struct Partial {
+    re: f64,
+    im: f64,
+    rotation: (f64, f64),
+    gain: f64,
+    /// Multiplied into `gain` every sample.
+    decay: f64,
+}
+
+impl Voice {
+    fn new(note: &Note, sample_rate: u32) -> Self {
+        let frequency = 440.0 * 2f64.powf((note.key as f64 - 69.0) / 12.0);
+        let partials = PARTIALS
+            .iter()
+            .enumerate()
+            .map(|(n, (loudness, decay))| (frequency * (n + 1) as f64, loudness, decay))
+            .take_while(|(frequency, _, _)| *frequency < sample_rate as f64 / 2.0)
+            .map(|(frequency, loudness, decay)| {
+                let step = TAU * frequency / sample_rate as f64;
+                Partial {
+                    re: 1.0,
+                    im: 0.0,
+                    rotation: (step.cos(), step.sin()),
+                    gain: *loudness as f64,
+                    decay: (-*decay as f64 / sample_rate as f64).exp(),
+                }
+            })
+            .collect();
+        Self {
+            partials,
+            amplitude: note.velocity as f32 / 127.0,
+            age: 0,
+            length: (note.duration as f64 * sample_rate as f64).round() as u64,
+        }
+    }
+
+    /// The current sample, then advances the voice by one.
+    fn next(&mut self, sample_rate: u32) -> f32 {
+        let t = self.age as f32 / sample_rate as f32;
+        let mut envelope = (t / ATTACK).min(1.0);
+        if self.age > self.length {
+            let released = (self.age - self.length) as f32 / sample_rate as f32;
+            envelope *= (1.0 - released / RELEASE).max(0.0);
+        }
+        let mut value = 0.0;
+        for partial in self.partials.iter_mut() {
+            value += partial.im * partial.gain;
+            let (cos, sin) = partial.rotation;
+            (partial.re, partial.im) = (
+                partial.re * cos - partial.im * sin,
+                partial.re * sin + partial.im * cos,
+            );
+            partial.gain *= partial.decay;
+        }
+        self.age += 1;
+        value as f32 * envelope * self.amplitude
+    }
+
+    fn finished(&self, sample_rate: u32) -> bool {
+        self.age > self.length + (RELEASE * sample_rate as f32) as u64
+    }
+}
@@ -0,0 +1,35 @@
+use std::io::{self, Write};
+
+/// Writes mono samples in -1..=1 as a 16-bit PCM WAV file.
+pub fn write(out: &mut impl Write, samples: &[f32], sample_rate: u32) -> io::Result<()> {
+    const CHANNELS: u16 = 1;
+    const BYTES_PER_SAMPLE: u16 = 2;
+    let data_len = u32::try_from(samples.len() * BYTES_PER_SAMPLE as usize)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too long for a wav file"))?;
+    let block_align = CHANNELS * BYTES_PER_SAMPLE;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // 1 = integer PCM
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&(BYTES_PER_SAMPLE * 8).to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&to_i16(*sample).to_le_bytes())?;
+    }
+    out.flush()
+}
+
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}